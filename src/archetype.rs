@@ -1,8 +1,23 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
 pub const INVALID_ARCHETYPE_INDEX: u32 = std::u32::MAX;
 pub const ROOT_ARCHETYPE_INDEX: u32 = 1;
 pub const LEAF_ARCHETYPE_INDEX: u32 = 2;
 pub const STEM_ARCHETYPE_INDEX: u32 = 4;
 pub const GROWING_BUD_ARCHETYPE_INDEX: u32 = 5;
+pub const FLOWER_ARCHETYPE_INDEX: u32 = 6;
+pub const FRUIT_ARCHETYPE_INDEX: u32 = 7;
+pub const SEED_ARCHETYPE_INDEX: u32 = 8;
+
+pub const PHYLLOTAXIS_ALTERNATE: u32 = 0; // One organ per node, successive nodes 180 degrees apart
+pub const PHYLLOTAXIS_OPPOSITE: u32 = 1; // Two organs per node, all pairs in the same plane
+pub const PHYLLOTAXIS_DECUSSATE: u32 = 2; // Two organs per node, successive pairs 90 degrees apart
+pub const PHYLLOTAXIS_SPIRAL: u32 = 3; // One organ per node, successive nodes divergenceAngle apart
+
+/// The divergence angle (in radians) found in most spiral phyllotaxis, about 137.5 degrees
+pub const GOLDEN_ANGLE: f32 = 2.399_963;
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -10,8 +25,232 @@ pub struct Archetype {
     pub color: [f32; 3],
 
     pub phototropism: f32,
+
+    // Arrangement of lateral organs produced by this archetype (one of the PHYLLOTAXIS constants)
+    pub phyllotaxis: u32,
+    // Rotation about the axis between successive nodes, only used for spiral phyllotaxis (radians)
+    pub divergenceAngle: f32,
+    // Angle between a lateral organ and the axis it grows out of (radians)
+    pub insertionAngle: f32,
+    // Maximum random deviation applied to each of the angles above (radians)
+    pub angleJitter: f32,
+
+    // Shape of leaf blades, see leaf::leaf_mesh. The blade is as wide as the node's radius
+    // Fraction of the leaf's length taken up by the petiole
+    pub petioleFraction: f32,
+    // Position of the widest point of the blade, as a fraction of its length from its base
+    pub bladeWidestPoint: f32,
+    // Exponent of the blade outline, zero for a rectangle, above one for narrow tips and bases
+    pub bladeOutlineExponent: f32,
+    // Angle the midrib bends downwards over the length of the blade (radians)
+    pub midribCurvature: f32,
+    // Angle the blade twists about the midrib from its base to its tip (radians)
+    pub laminaTwist: f32,
+    // Angle between the leaf and the stem it grows from (radians), zero to follow the node
+    pub leafAngle: f32,
+
+    // Carbon produced per unit of projected area per tick
+    pub photosynthesisRate: f32,
+    // Carbon consumed per unit of volume per tick
+    pub respirationRate: f32,
+
+    // Age the plant must reach before buds may turn into flowers
+    pub floweringAge: u32,
+    // Chance per tick that a bud on a plant old enough to flower turns into a flower
+    pub floweringProbability: f32,
+    // Age at which a flower sets fruit, or a fruit is ripe
+    pub maturationAge: u32,
+    // Carbon the plant invests into every seed
+    pub seedCost: f32,
+    // Maximum number of seeds released by one fruit
+    pub seedsPerFruit: u32,
+    // Mean distance a seed travels from its parent
+    pub dispersalDistance: f32,
+
+    // Mean moisture of the surrounding cells required before a seed may germinate
+    pub germinationMoisture: u32,
+    // Mean temperature of the surrounding cells required before a seed may germinate
+    pub germinationTemperature: u32,
+    // Chance per tick that a seed germinates once both thresholds are met
+    pub germinationProbability: f32,
+    // Number of ticks a seed stays viable in the soil
+    pub dormancyLimit: u32,
+
+    // Age at which a living organ dies of old age, zero if it never does
+    pub maxAge: u32,
+    // Fraction of the remaining volume of a dead organ that decomposes every tick
+    pub decayRate: f32,
+}
+
+impl Default for Archetype {
+    fn default() -> Archetype {
+        Archetype::new()
+    }
+}
+
+impl Archetype {
+    pub fn new() -> Archetype {
+        Archetype {
+            color: [0.0, 1.0, 0.0],
+            phototropism: 0.0,
+            phyllotaxis: PHYLLOTAXIS_SPIRAL,
+            divergenceAngle: GOLDEN_ANGLE,
+            insertionAngle: FRAC_PI_4,
+            angleJitter: 0.0,
+            petioleFraction: 0.0,
+            bladeWidestPoint: 0.5,
+            bladeOutlineExponent: 0.0,
+            midribCurvature: 0.0,
+            laminaTwist: 0.0,
+            leafAngle: 0.0,
+            photosynthesisRate: 0.0,
+            respirationRate: 0.0,
+            floweringAge: 0,
+            floweringProbability: 0.0,
+            maturationAge: 0,
+            seedCost: 0.0,
+            seedsPerFruit: 0,
+            dispersalDistance: 0.0,
+            germinationMoisture: 0,
+            germinationTemperature: 0,
+            germinationProbability: 0.0,
+            dormancyLimit: 0,
+            maxAge: 0,
+            decayRate: 0.001,
+        }
+    }
+
+    /// Returns the rotation about the axis between two successive nodes
+    pub fn divergence(&self) -> f32 {
+        match self.phyllotaxis {
+            PHYLLOTAXIS_ALTERNATE => PI,
+            PHYLLOTAXIS_OPPOSITE => 0.0,
+            PHYLLOTAXIS_DECUSSATE => FRAC_PI_2,
+            _ => self.divergenceAngle,
+        }
+    }
+
+    /// Returns how many lateral organs are produced at a single node
+    pub fn organs_per_node(&self) -> u32 {
+        match self.phyllotaxis {
+            PHYLLOTAXIS_OPPOSITE | PHYLLOTAXIS_DECUSSATE => 2,
+            _ => 1,
+        }
+    }
 }
 
 pub struct ArchetypeTable {
     table: Vec<Archetype>,
 }
+
+impl Default for ArchetypeTable {
+    fn default() -> ArchetypeTable {
+        ArchetypeTable::new()
+    }
+}
+
+impl ArchetypeTable {
+    /// Creates a table with default parameters for all of the builtin archetypes
+    pub fn new() -> ArchetypeTable {
+        let mut table = ArchetypeTable {
+            table: vec![Archetype::new(); SEED_ARCHETYPE_INDEX as usize + 1],
+        };
+
+        let mut stem = Archetype::new();
+        stem.color = [0.4, 0.55, 0.2];
+        stem.photosynthesisRate = 0.01;
+        stem.respirationRate = 0.05;
+        table.set(STEM_ARCHETYPE_INDEX, stem);
+
+        let mut root = Archetype::new();
+        root.color = [0.6, 0.4, 0.2];
+        root.respirationRate = 0.05;
+        table.set(ROOT_ARCHETYPE_INDEX, root);
+
+        let mut leaf = Archetype::new();
+        leaf.color = [0.15, 0.6, 0.15];
+        leaf.photosynthesisRate = 0.05;
+        leaf.respirationRate = 0.05;
+        leaf.maxAge = 8000;
+        leaf.decayRate = 0.005;
+        leaf.petioleFraction = 0.2;
+        leaf.bladeWidestPoint = 0.4;
+        leaf.bladeOutlineExponent = 1.0;
+        leaf.midribCurvature = 0.6;
+        leaf.laminaTwist = 0.2;
+        leaf.leafAngle = FRAC_PI_4;
+        table.set(LEAF_ARCHETYPE_INDEX, leaf);
+
+        let mut bud = Archetype::new();
+        bud.color = [0.55, 0.85, 0.3];
+        bud.angleJitter = 0.1;
+        bud.floweringAge = 3000;
+        bud.floweringProbability = 0.0005;
+        table.set(GROWING_BUD_ARCHETYPE_INDEX, bud);
+
+        let mut flower = Archetype::new();
+        flower.color = [1.0, 1.0, 1.0];
+        flower.maturationAge = 500;
+        flower.decayRate = 0.01;
+        table.set(FLOWER_ARCHETYPE_INDEX, flower);
+
+        let mut fruit = Archetype::new();
+        fruit.color = [1.0, 0.0, 0.0];
+        fruit.respirationRate = 0.05;
+        fruit.maturationAge = 1000;
+        fruit.seedCost = 0.0001;
+        fruit.seedsPerFruit = 3;
+        fruit.dispersalDistance = 2.0;
+        fruit.decayRate = 0.01;
+        table.set(FRUIT_ARCHETYPE_INDEX, fruit);
+
+        let mut seed = Archetype::new();
+        seed.germinationMoisture = 30;
+        seed.germinationTemperature = 10;
+        seed.germinationProbability = 0.01;
+        seed.dormancyLimit = 20000;
+        table.set(SEED_ARCHETYPE_INDEX, seed);
+
+        table
+    }
+
+    /// Returns the archetype at index, or None if the index lies beyond the table
+    pub fn get(&self, index: u32) -> Option<&Archetype> {
+        self.table.get(index as usize)
+    }
+
+    /// Sets the archetype at index, growing the table if needed
+    pub fn set(&mut self, index: u32, archetype: Archetype) {
+        if index as usize >= self.table.len() {
+            self.table.resize(index as usize + 1, Archetype::new());
+        }
+        self.table[index as usize] = archetype;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phyllotaxis_sets_divergence_and_organs_per_node() {
+        let mut archetype = Archetype::new();
+        archetype.divergenceAngle = 1.0;
+        for (phyllotaxis, divergence, organs) in [
+            (PHYLLOTAXIS_ALTERNATE, PI, 1),
+            (PHYLLOTAXIS_OPPOSITE, 0.0, 2),
+            (PHYLLOTAXIS_DECUSSATE, FRAC_PI_2, 2),
+            (PHYLLOTAXIS_SPIRAL, 1.0, 1),
+        ] {
+            archetype.phyllotaxis = phyllotaxis;
+            assert_eq!(archetype.divergence(), divergence);
+            assert_eq!(archetype.organs_per_node(), organs);
+        }
+    }
+
+    #[test]
+    fn golden_angle_divides_the_circle_in_the_golden_ratio() {
+        let ratio = (1.0 + 5.0f32.sqrt()) / 2.0;
+        assert!((GOLDEN_ANGLE - 2.0 * PI * (1.0 - 1.0 / ratio)).abs() < 1.0e-5);
+    }
+}
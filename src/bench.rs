@@ -0,0 +1,240 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+use std::time::{Duration, Instant};
+
+use super::archetype::*;
+use super::metamer::*;
+use super::node::*;
+use super::plant::*;
+use super::slot::*;
+
+/// Number of nodes in the benchmark forest
+pub const BENCH_NODES: u32 = 1_000_000;
+/// Number of nodes in every plant of the benchmark forest
+const NODES_PER_PLANT: u32 = 1000;
+/// Number of ticks every benchmark is run for
+const BENCH_TICKS: u32 = 20;
+
+/// Builds a forest of plants with node_count nodes between them. Every plant is a chain of stem
+/// segments with a leaf growing from each of them. There are no buds, so nothing divides and every
+/// tick does the same amount of work
+pub fn forest(node_count: u32) -> (NodeBuffer, PlantBuffer) {
+    let plant_count = (node_count / NODES_PER_PLANT).max(1);
+    let mut plant_buffer = PlantBuffer::new(plant_count);
+    let mut node_buffer = NodeBuffer::new(node_count);
+    for p in 0..plant_count {
+        let mut plant = Plant::new();
+        plant.status = STATUS_ALIVE;
+        plant.location = [p as f32, 0.0, 0.0];
+        plant.carbon = 1.0;
+        let plant_handle = plant_buffer.alloc_insert(plant);
+
+        let mut parent = INVALID_INDEX;
+        for n in 0..NODES_PER_PLANT.min(node_count) {
+            let mut node = Node::new();
+            node.status = STATUS_ALIVE;
+            node.visible = 1;
+            node.set_plant(plant_handle);
+            node.length = 0.01;
+            node.radius = 0.001;
+            node.archetypeId = if n % 2 == 0 {
+                STEM_ARCHETYPE_INDEX
+            } else {
+                LEAF_ARCHETYPE_INDEX
+            };
+            node.volume = cylinder_volume(node);
+            let index = node_buffer.alloc();
+            node_buffer.set(index, node);
+            // Leaves and the next stem segment both grow out of the stem segment before them, and
+            // the next stem segment continues its axis
+            if parent != INVALID_INDEX && n % 2 == 0 {
+                node_buffer.add_first_child(parent, index);
+            } else if parent != INVALID_INDEX {
+                node_buffer.add_child(parent, index);
+            }
+            if n % 2 == 0 {
+                parent = index;
+            }
+        }
+    }
+    (node_buffer, plant_buffer)
+}
+
+/// The node update of NodeBuffer::update_all for a buffer of whole nodes, as nodes were stored
+/// before they were split into columns. It is kept as a reference to measure the columns against,
+/// and does the same work: plants are written back once per run of their nodes, and nodes only
+/// count as changed if their shape did. Buds are left out, as the benchmark forest has none
+fn update_rows(
+    rows: &mut SlotBuffer<Vec<Node>>,
+    archetype_table: &ArchetypeTable,
+    plant_buffer: &mut PlantBuffer,
+    stem_growth: &impl MetamerRule,
+) -> u32 {
+    let mut changed = 0;
+    let positions = number_metamers(rows.size(), |index| {
+        let node = &rows[index];
+        (node.status != STATUS_GARBAGE).then_some((
+            node.archetypeId,
+            node.parentIndex,
+            node.firstChildIndex,
+        ))
+    });
+    let mut current_plant: Option<(PlantHandle, Plant)> = None;
+    for ni in 0..rows.size() {
+        let node = &mut rows[ni];
+        if node.status == STATUS_GARBAGE {
+            continue;
+        }
+        node.age += 1;
+        if node.status != STATUS_ALIVE || node.archetypeId == INVALID_ARCHETYPE_INDEX {
+            continue;
+        }
+        let archetype = match archetype_table.get(node.archetypeId) {
+            Some(archetype) => archetype,
+            None => continue,
+        };
+        let plant_handle = node.plant_handle();
+        if !current_plant.is_some_and(|(handle, _)| handle == plant_handle) {
+            if let Some((handle, plant)) = current_plant {
+                plant_buffer.set(handle.index, plant);
+            }
+            current_plant = plant_buffer
+                .get_handle(plant_handle)
+                .map(|plant| (plant_handle, plant));
+        }
+        let plant = match current_plant.as_mut() {
+            Some((_, plant)) => plant,
+            None => continue,
+        };
+        plant.carbon += archetype.photosynthesisRate * node.length * node.radius * 2.0
+            - archetype.respirationRate * node.volume;
+        let (length, radius) = (node.length, node.radius);
+        let grown = |max_length, length_scale, max_radius, radius_scale| {
+            (
+                length + logisticDelta(length, max_length, length_scale),
+                radius + logisticDelta(radius, max_radius, radius_scale),
+            )
+        };
+        (node.length, node.radius) = match node.archetypeId {
+            ROOT_ARCHETYPE_INDEX => grown(0.2, 0.5, 0.01, 0.5),
+            STEM_ARCHETYPE_INDEX => {
+                let (rank, phytomer) = positions[ni as usize];
+                stem_growth.grow(length, radius, rank, phytomer)
+            }
+            LEAF_ARCHETYPE_INDEX => grown(0.3, 0.1, 0.05, 0.5),
+            _ => continue,
+        };
+        if node.length != length || node.radius != radius {
+            changed += 1;
+            node.volume = cylinder_volume(*node);
+        }
+    }
+    if let Some((handle, plant)) = current_plant {
+        plant_buffer.set(handle.index, plant);
+    }
+    changed
+}
+
+/// Runs f BENCH_TICKS times and returns the mean time per tick
+fn time_ticks(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..BENCH_TICKS {
+        f();
+    }
+    start.elapsed() / BENCH_TICKS
+}
+
+fn report(name: &str, rows: Duration, columns: Duration, node_count: u32) {
+    println!(
+        "{:<24} rows {:>8.2} ms ({:>5.1} ns/node)  columns {:>8.2} ms ({:>5.1} ns/node)  speedup {:.2}x",
+        name,
+        rows.as_secs_f64() * 1e3,
+        rows.as_secs_f64() * 1e9 / node_count as f64,
+        columns.as_secs_f64() * 1e3,
+        columns.as_secs_f64() * 1e9 / node_count as f64,
+        rows.as_secs_f64() / columns.as_secs_f64(),
+    );
+}
+
+/// Compares passes over a forest of BENCH_NODES nodes stored as columns in a NodeBuffer with the
+/// same passes over whole nodes, and prints the time each of them takes. Should be run in a
+/// release build
+pub fn run_benchmarks() {
+    let archetype_table = ArchetypeTable::new();
+    let (mut node_buffer, mut plant_buffer) = forest(BENCH_NODES);
+    // Copy every slot, so that both layouts have the same nodes at the same indices
+    let mut rows = SlotBuffer::<Vec<Node>>::new(node_buffer.size());
+    for index in rows.try_alloc_many(node_buffer.size()).unwrap() {
+        rows.set(index, node_buffer.get(index));
+    }
+    let node_count = node_buffer.current_size();
+    println!(
+        "{} nodes in {} plants, mean of {} ticks",
+        node_count,
+        plant_buffer.current_size(),
+        BENCH_TICKS
+    );
+
+    let stem_growth = TaperedElongation::new();
+    let mut row_plants = plant_buffer.clone();
+    let row_update = time_ticks(|| {
+        update_rows(&mut rows, &archetype_table, &mut row_plants, &stem_growth);
+    });
+    let column_update = time_ticks(|| {
+        node_buffer
+            .update_all(&archetype_table, &mut plant_buffer, &stem_growth)
+            .unwrap()
+    });
+    report("update_all", row_update, column_update, node_count);
+
+    let mut live = 0;
+    let row_scan = time_ticks(|| {
+        live = (0..rows.size())
+            .filter(|&i| rows[i].status == STATUS_ALIVE)
+            .count()
+    });
+    let column_scan = time_ticks(|| {
+        live = node_buffer
+            .columns()
+            .status
+            .iter()
+            .filter(|&&status| status == STATUS_ALIVE)
+            .count()
+    });
+    report("count living nodes", row_scan, column_scan, node_count);
+    println!("{} living nodes", live);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_and_columns_update_alike() {
+        let archetype_table = ArchetypeTable::new();
+        let stem_growth = TaperedElongation::new();
+        let (mut node_buffer, mut plant_buffer) = forest(2 * NODES_PER_PLANT);
+        let mut rows = SlotBuffer::<Vec<Node>>::new(node_buffer.size());
+        for index in rows.try_alloc_many(node_buffer.size()).unwrap() {
+            rows.set(index, node_buffer.get(index));
+        }
+        let mut row_plants = plant_buffer.clone();
+
+        for _ in 0..10 {
+            update_rows(&mut rows, &archetype_table, &mut row_plants, &stem_growth);
+            node_buffer
+                .update_all(&archetype_table, &mut plant_buffer, &stem_growth)
+                .unwrap();
+        }
+        for index in 0..rows.size() {
+            let (row, column) = (rows[index], node_buffer.get(index));
+            assert_eq!(row.age, column.age);
+            assert_eq!(row.length, column.length);
+            assert_eq!(row.radius, column.radius);
+            assert!((row.volume - column.volume).abs() <= row.volume * 1.0e-6);
+        }
+        for (index, plant) in plant_buffer.iter() {
+            assert!((row_plants.get(index).carbon - plant.carbon).abs() < 1.0e-6);
+        }
+    }
+}
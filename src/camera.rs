@@ -90,6 +90,11 @@ impl Camera {
         self.projection * view
     }
 
+    /// Returns the position of the camera
+    pub fn loc(&self) -> Point3<f32> {
+        self.loc
+    }
+
     pub fn translate(&mut self, delta: Vector3<f32>) {
         self.loc = self.loc + delta;
     }
@@ -0,0 +1,577 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+use std::fmt;
+
+use super::archetype::*;
+use super::node::*;
+use super::plant::*;
+
+/// Colour of dead tissue
+const DEAD_COLOR: [f32; 3] = [0.45, 0.3, 0.15];
+/// Colour tissue turns as it senesces
+const SENESCENT_COLOR: [f32; 3] = [0.85, 0.75, 0.2];
+/// Colour tissue turns as it wilts from drought
+const WILTED_COLOR: [f32; 3] = [0.6, 0.55, 0.35];
+/// Fraction of an organ's maximum age from which it starts to senesce
+const SENESCENCE_ONSET: f32 = 0.8;
+/// Age from which tissue stops darkening
+const MATURE_AGE: u32 = 5000;
+/// How much darker mature tissue is than new tissue
+const AGE_DARKENING: f32 = 0.4;
+
+/// Which states of a node shade the colour of its archetype, see node_color
+#[derive(Clone, Copy, Debug)]
+pub struct Shading {
+    pub age: bool,         // New tissue is bright, and darkens as it matures
+    pub waterStress: bool, // Tissue of plants under water stress wilts to a dull brown
+    pub senescence: bool,  // Organs yellow towards their maximum age, dead ones are brown
+}
+
+impl Default for Shading {
+    fn default() -> Shading {
+        Shading::new()
+    }
+}
+
+impl Shading {
+    /// Shades by every state
+    pub fn new() -> Shading {
+        Shading {
+            age: true,
+            waterStress: true,
+            senescence: true,
+        }
+    }
+
+    /// Leaves archetype colours as they are
+    pub fn none() -> Shading {
+        Shading {
+            age: false,
+            waterStress: false,
+            senescence: false,
+        }
+    }
+}
+
+/// Mixes t of b into a, with t clamped to [0, 1]
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    let t = t.clamp(0.0, 1.0);
+    [
+        a[0] + (b[0] - a[0]) * t,
+        a[1] + (b[1] - a[1]) * t,
+        a[2] + (b[2] - a[2]) * t,
+    ]
+}
+
+/// Returns the colour node is drawn in: the colour of its archetype, shaded by the states of the
+/// node and of its plant that are switched on in shading
+pub fn node_color(
+    node: &Node,
+    archetype: &Archetype,
+    plant: Option<&Plant>,
+    shading: &Shading,
+) -> [f32; 4] {
+    let mut color = archetype.color;
+    if shading.age {
+        let maturity = (node.age as f32 / MATURE_AGE as f32).min(1.0);
+        color = color.map(|c| c * (1.0 - AGE_DARKENING * maturity));
+    }
+    if let Some(plant) = plant.filter(|_| shading.waterStress) {
+        color = mix(color, WILTED_COLOR, plant.waterStress);
+    }
+    if shading.senescence {
+        if node.status == STATUS_DEAD {
+            color = DEAD_COLOR;
+        } else if archetype.maxAge != 0 {
+            let lifetime = node.age as f32 / archetype.maxAge as f32;
+            let progress = (lifetime - SENESCENCE_ONSET) / (1.0 - SENESCENCE_ONSET);
+            color = mix(color, SENESCENT_COLOR, progress);
+        }
+    }
+    [color[0], color[1], color[2], 1.0]
+}
+
+/// A quantity nodes can be coloured by, see NodeBuffer::attribute_values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attribute {
+    Age,
+    Radius,
+    Length,
+    Volume,
+    /// Nodes don't hold water themselves, so this is the water content of their plant, from 0
+    /// (dying of drought) to 1 (well watered)
+    WaterContent,
+    /// Carbohydrate reserves of the node's plant
+    Carbohydrate,
+    /// Number of lateral branchings between the node and its root
+    BranchOrder,
+    /// Index of the node's plant
+    PlantId,
+}
+
+impl Attribute {
+    pub const ALL: [Attribute; 8] = [
+        Attribute::Age,
+        Attribute::Radius,
+        Attribute::Length,
+        Attribute::Volume,
+        Attribute::WaterContent,
+        Attribute::Carbohydrate,
+        Attribute::BranchOrder,
+        Attribute::PlantId,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Attribute::Age => "age",
+            Attribute::Radius => "radius",
+            Attribute::Length => "length",
+            Attribute::Volume => "volume",
+            Attribute::WaterContent => "water content",
+            Attribute::Carbohydrate => "carbohydrate",
+            Attribute::BranchOrder => "branch order",
+            Attribute::PlantId => "plant id",
+        }
+    }
+}
+
+/// Control points of the viridis colour map, evenly spaced from 0 to 1
+const VIRIDIS: [[f32; 3]; 6] = [
+    [0.267, 0.005, 0.329],
+    [0.254, 0.265, 0.530],
+    [0.164, 0.471, 0.558],
+    [0.135, 0.659, 0.518],
+    [0.478, 0.820, 0.3176],
+    [0.993, 0.906, 0.144],
+];
+
+/// Turns values between 0 and 1 into colours
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMap {
+    /// Dark blue through green to yellow, with brightness rising evenly along the way
+    Viridis,
+    /// Red through yellow, green, cyan and blue to magenta, which tells neighbouring values apart
+    /// better, for instance plant ids
+    Rainbow,
+    Grayscale,
+}
+
+impl ColorMap {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorMap::Viridis => "viridis",
+            ColorMap::Rainbow => "rainbow",
+            ColorMap::Grayscale => "grayscale",
+        }
+    }
+
+    /// Returns the map after this one, wrapping around, for cycling through them in the viewer
+    pub fn next(&self) -> ColorMap {
+        match self {
+            ColorMap::Viridis => ColorMap::Rainbow,
+            ColorMap::Rainbow => ColorMap::Grayscale,
+            ColorMap::Grayscale => ColorMap::Viridis,
+        }
+    }
+
+    /// Returns the colour of t, which is clamped to [0, 1]
+    pub fn sample(&self, t: f32) -> [f32; 4] {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+        let color = match self {
+            ColorMap::Viridis => {
+                let x = t * (VIRIDIS.len() - 1) as f32;
+                let i = (x as usize).min(VIRIDIS.len() - 2);
+                mix(VIRIDIS[i], VIRIDIS[i + 1], x - i as f32)
+            }
+            ColorMap::Rainbow => {
+                // Hue from 0 to 300 degrees at full saturation and value
+                let h = t * 5.0;
+                let channel = |n: f32| {
+                    let k = (n + h) % 6.0;
+                    1.0 - (k.min(4.0 - k).clamp(0.0, 1.0))
+                };
+                [channel(5.0), channel(3.0), channel(1.0)]
+            }
+            ColorMap::Grayscale => [t, t, t],
+        };
+        [color[0], color[1], color[2], 1.0]
+    }
+}
+
+/// How gen_vertex colours nodes
+#[derive(Clone, Copy, Debug)]
+pub enum NodeColors {
+    /// In the colours of their archetypes, see node_color
+    Archetype(Shading),
+    /// By the value of attribute, mapped linearly from [min, max] onto map
+    Attribute {
+        attribute: Attribute,
+        map: ColorMap,
+        min: f32,
+        max: f32,
+    },
+}
+
+/// Formats value to four significant figures, switching to scientific notation for values too
+/// small or large to read that way
+fn significant(value: f32) -> String {
+    let magnitude = value.abs();
+    if magnitude != 0.0 && !(1e-3..1e6).contains(&magnitude) {
+        format!("{:.3e}", value)
+    } else {
+        // Digits after the point that leave four significant figures
+        let exponent = if magnitude == 0.0 {
+            0
+        } else {
+            magnitude.log10().floor() as i32
+        };
+        format!("{:.*}", (3 - exponent).max(0) as usize, value)
+    }
+}
+
+impl fmt::Display for NodeColors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeColors::Archetype(_) => write!(f, "archetype colours"),
+            NodeColors::Attribute {
+                attribute,
+                map,
+                min,
+                max,
+            } => write!(
+                f,
+                "{} from {} to {} ({})",
+                attribute.name(),
+                significant(*min),
+                significant(*max),
+                map.name()
+            ),
+        }
+    }
+}
+
+impl NodeBuffer {
+    /// Returns the branch order of every slot of the buffer, indexed by slot. The first child
+    /// continues its parent's axis, and every other child starts a branch one order higher
+    pub fn branch_orders(&self) -> Vec<u32> {
+        let nodes = self.columns();
+        let mut orders = vec![0; self.size() as usize];
+        for root in self.roots() {
+            for index in self.depth_first(root) {
+                let parent = nodes.parentIndex[index as usize];
+                if parent != INVALID_INDEX {
+                    let lateral = nodes.firstChildIndex[parent as usize] != index;
+                    orders[index as usize] = orders[parent as usize] + lateral as u32;
+                }
+            }
+        }
+        orders
+    }
+
+    /// Returns the value of attribute for every slot of the buffer, indexed by slot. Garbage
+    /// slots, and nodes whose plant is gone where the attribute is taken from the plant, get 0
+    pub fn attribute_values(&self, attribute: Attribute, plant_buffer: &PlantBuffer) -> Vec<f32> {
+        let nodes = self.columns();
+        let orders = match attribute {
+            Attribute::BranchOrder => self.branch_orders(),
+            _ => Vec::new(),
+        };
+        let mut values = vec![0.0; self.size() as usize];
+        for index in self.live_indices() {
+            let i = index as usize;
+            let plant = || plant_buffer.get_handle(nodes.plant_handle(i));
+            values[i] = match attribute {
+                Attribute::Age => nodes.age[i] as f32,
+                Attribute::Radius => nodes.radius[i],
+                Attribute::Length => nodes.length[i],
+                Attribute::Volume => nodes.volume[i],
+                Attribute::WaterContent => plant().map_or(0.0, |plant| 1.0 - plant.waterStress),
+                Attribute::Carbohydrate => plant().map_or(0.0, |plant| plant.carbon),
+                Attribute::BranchOrder => orders[i] as f32,
+                Attribute::PlantId => nodes.plantId[i] as f32,
+            };
+        }
+        values
+    }
+
+    /// Returns the smallest and largest values of attribute over the visible nodes, or None if no
+    /// node is visible
+    pub fn attribute_range(
+        &self,
+        attribute: Attribute,
+        plant_buffer: &PlantBuffer,
+    ) -> Option<(f32, f32)> {
+        let values = self.attribute_values(attribute, plant_buffer);
+        let nodes = self.columns();
+        self.live_indices()
+            .filter(|&index| nodes.visible[index as usize] == 1)
+            .map(|index| values[index as usize])
+            .fold(None, |range, value| match range {
+                None => Some((value, value)),
+                Some((min, max)) => Some((f32::min(min, value), f32::max(max, value))),
+            })
+    }
+
+    /// Returns the colour of every slot of the buffer, indexed by slot
+    pub fn node_colors(
+        &self,
+        colors: &NodeColors,
+        plant_buffer: &PlantBuffer,
+        archetype_table: &ArchetypeTable,
+    ) -> Vec<[f32; 4]> {
+        match *colors {
+            NodeColors::Archetype(shading) => {
+                // Nodes of unknown archetypes are drawn with the default colour
+                let default = Archetype::new();
+                (0..self.size())
+                    .map(|index| {
+                        let node = self.get(index);
+                        let archetype = archetype_table.get(node.archetypeId).unwrap_or(&default);
+                        let plant = plant_buffer.get_handle(node.plant_handle());
+                        node_color(&node, archetype, plant.as_ref(), &shading)
+                    })
+                    .collect()
+            }
+            NodeColors::Attribute {
+                attribute,
+                map,
+                min,
+                max,
+            } => {
+                // A range of a single value maps everything to the middle of the map
+                let span = max - min;
+                self.attribute_values(attribute, plant_buffer)
+                    .into_iter()
+                    .map(|value| {
+                        if span > 0.0 {
+                            map.sample((value - min) / span)
+                        } else {
+                            map.sample(0.5)
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    fn close(a: [f32; 4], b: [f32; 3]) -> bool {
+        a[3] == 1.0 && (0..3).all(|i| (a[i] - b[i]).abs() < 1.0e-6)
+    }
+
+    fn archetype() -> Archetype {
+        let mut archetype = Archetype::new();
+        archetype.color = [0.2, 0.6, 0.4];
+        archetype.maxAge = 1000;
+        archetype
+    }
+
+    fn node(age: u32, status: u32) -> Node {
+        let mut node = Node::new();
+        node.age = age;
+        node.status = status;
+        node
+    }
+
+    #[test]
+    fn unshaded_nodes_take_their_archetype_colour() {
+        let archetype = archetype();
+        let mut plant = Plant::new();
+        plant.waterStress = 1.0;
+        let color = node_color(
+            &node(1000, STATUS_DEAD),
+            &archetype,
+            Some(&plant),
+            &Shading::none(),
+        );
+        assert!(close(color, archetype.color));
+    }
+
+    #[test]
+    fn tissue_darkens_until_it_is_mature() {
+        let archetype = archetype();
+        let shading = Shading {
+            age: true,
+            ..Shading::none()
+        };
+        let new = node_color(&node(0, STATUS_ALIVE), &archetype, None, &shading);
+        let mature = node_color(&node(MATURE_AGE, STATUS_ALIVE), &archetype, None, &shading);
+        let old = node_color(
+            &node(2 * MATURE_AGE, STATUS_ALIVE),
+            &archetype,
+            None,
+            &shading,
+        );
+        assert!(close(new, archetype.color));
+        assert!(close(
+            mature,
+            archetype.color.map(|c| c * (1.0 - AGE_DARKENING))
+        ));
+        assert_eq!(old, mature);
+    }
+
+    #[test]
+    fn stressed_plants_wilt() {
+        let archetype = archetype();
+        let shading = Shading {
+            waterStress: true,
+            ..Shading::none()
+        };
+        let mut plant = Plant::new();
+        plant.waterStress = 1.0;
+        let alive = node(0, STATUS_ALIVE);
+        let wilted = node_color(&alive, &archetype, Some(&plant), &shading);
+        assert!(close(wilted, WILTED_COLOR));
+        // Nodes whose plant is gone aren't shaded by it
+        assert!(close(
+            node_color(&alive, &archetype, None, &shading),
+            archetype.color
+        ));
+    }
+
+    #[test]
+    fn organs_yellow_towards_their_maximum_age_and_brown_when_dead() {
+        let archetype = archetype();
+        let shading = Shading {
+            senescence: true,
+            ..Shading::none()
+        };
+        let color = |age, status| node_color(&node(age, status), &archetype, None, &shading);
+        assert!(close(color(800, STATUS_ALIVE), archetype.color));
+        assert!(close(
+            color(900, STATUS_ALIVE),
+            mix(archetype.color, SENESCENT_COLOR, 0.5)
+        ));
+        assert!(close(color(1000, STATUS_ALIVE), SENESCENT_COLOR));
+        assert!(close(color(10, STATUS_DEAD), DEAD_COLOR));
+    }
+
+    #[test]
+    fn nodes_of_unknown_archetypes_take_the_default_colour() {
+        let (plant_buffer, plant) = living_plant();
+        let mut node_buffer = NodeBuffer::new(1);
+        let index = organ(&mut node_buffer, plant, 100, 0.0);
+        let colors = node_buffer.node_colors(
+            &NodeColors::Archetype(Shading::none()),
+            &plant_buffer,
+            &ArchetypeTable::new(),
+        );
+        assert!(close(colors[index as usize], Archetype::new().color));
+    }
+
+    #[test]
+    fn maps_run_from_end_to_end() {
+        assert!(close(ColorMap::Viridis.sample(0.0), VIRIDIS[0]));
+        assert!(close(ColorMap::Viridis.sample(1.0), VIRIDIS[5]));
+        assert!(close(
+            ColorMap::Viridis.sample(0.1),
+            mix(VIRIDIS[0], VIRIDIS[1], 0.5)
+        ));
+        assert!(close(ColorMap::Rainbow.sample(0.0), [1.0, 0.0, 0.0]));
+        assert!(close(ColorMap::Rainbow.sample(0.4), [0.0, 1.0, 0.0]));
+        assert!(close(ColorMap::Rainbow.sample(1.0), [1.0, 0.0, 1.0]));
+        assert!(close(ColorMap::Grayscale.sample(0.25), [0.25; 3]));
+    }
+
+    #[test]
+    fn samples_outside_the_map_are_clamped() {
+        for map in [ColorMap::Viridis, ColorMap::Rainbow, ColorMap::Grayscale] {
+            assert_eq!(map.sample(-1.0), map.sample(0.0));
+            assert_eq!(map.sample(f32::NAN), map.sample(0.0));
+            assert_eq!(map.sample(2.0), map.sample(1.0));
+        }
+        let mut map = ColorMap::Viridis;
+        for _ in 0..3 {
+            map = map.next();
+        }
+        assert_eq!(map, ColorMap::Viridis);
+    }
+
+    #[test]
+    fn laterals_start_a_higher_branch_order() {
+        let (_, plant) = living_plant();
+        let (mut node_buffer, [lower, upper, leaf, bud]) = shoot(plant);
+        let twig = organ(&mut node_buffer, plant, STEM_ARCHETYPE_INDEX, 0.5);
+        node_buffer.add_child(leaf, twig);
+        let orders = node_buffer.branch_orders();
+        assert_eq!(orders[lower as usize], 0);
+        assert_eq!(orders[upper as usize], 0);
+        assert_eq!(orders[leaf as usize], 1);
+        assert_eq!(orders[bud as usize], 1);
+        assert_eq!(orders[twig as usize], 1);
+    }
+
+    #[test]
+    fn range_covers_the_visible_nodes() {
+        let (mut plant_buffer, plant) = living_plant();
+        let (mut node_buffer, [lower, upper, leaf, bud]) = shoot(plant);
+        for (index, age) in [(lower, 30), (upper, 10), (leaf, 20), (bud, 1000)] {
+            let mut node = node_buffer.get(index);
+            node.age = age;
+            node.visible = (index != bud) as u32;
+            node_buffer.set(index, node);
+        }
+        assert_eq!(
+            node_buffer.attribute_range(Attribute::Age, &plant_buffer),
+            Some((10.0, 30.0))
+        );
+        assert_eq!(
+            node_buffer.attribute_range(Attribute::BranchOrder, &plant_buffer),
+            Some((0.0, 1.0))
+        );
+
+        // Nodes whose plant is gone have no water
+        let values = node_buffer.attribute_values(Attribute::WaterContent, &plant_buffer);
+        assert_eq!(values[lower as usize], 1.0);
+        plant_buffer.free(plant.index);
+        let values = node_buffer.attribute_values(Attribute::WaterContent, &plant_buffer);
+        assert_eq!(values[lower as usize], 0.0);
+        assert_eq!(
+            NodeBuffer::new(1).attribute_range(Attribute::Age, &plant_buffer),
+            None
+        );
+    }
+
+    #[test]
+    fn attribute_colours_span_the_map() {
+        let (plant_buffer, plant) = living_plant();
+        let (node_buffer, [lower, upper, ..]) = shoot(plant);
+        let colors = |min, max| {
+            node_buffer.node_colors(
+                &NodeColors::Attribute {
+                    attribute: Attribute::BranchOrder,
+                    map: ColorMap::Grayscale,
+                    min,
+                    max,
+                },
+                &plant_buffer,
+                &ArchetypeTable::new(),
+            )
+        };
+        let spread = colors(0.0, 2.0);
+        assert!(close(spread[lower as usize], [0.0; 3]));
+        let leaf = node_buffer.children(lower).nth(1).unwrap();
+        assert!(close(spread[leaf as usize], [0.5; 3]));
+        // A range of a single value maps everything to the middle
+        assert!(close(colors(1.0, 1.0)[upper as usize], [0.5; 3]));
+    }
+
+    #[test]
+    fn ranges_are_shown_to_four_significant_figures() {
+        let colors = NodeColors::Attribute {
+            attribute: Attribute::Volume,
+            map: ColorMap::Viridis,
+            min: 0.012345,
+            max: 1234567.0,
+        };
+        assert_eq!(
+            colors.to_string(),
+            "volume from 0.01235 to 1.235e6 (viridis)"
+        );
+    }
+}
@@ -0,0 +1,284 @@
+#![allow(dead_code)]
+use cgmath::{InnerSpace, MetricSpace, Vector3};
+use std::f32::consts::PI;
+
+use super::vertex::mVertex as Vertex;
+
+/// Fewest sides a cylinder is given, whatever its resolution
+pub const MIN_SIDES: u32 = 3;
+/// Most sides a cylinder is given, whatever its resolution
+pub const MAX_SIDES: u32 = 32;
+
+/// How many sides the rings of a generalised cylinder get
+#[derive(Clone, Copy, Debug)]
+pub enum Resolution {
+    /// Every ring gets the same number of sides
+    Fixed(u32),
+    /// Sides are spacing apart around the ring, so thick stems get more sides than thin twigs
+    ByRadius { spacing: f32 },
+    /// Every side spans about angle radians as seen from eye, so rings far away from it get
+    /// fewer sides
+    ByDistance { eye: Vector3<f32>, angle: f32 },
+}
+
+impl Resolution {
+    /// Returns the number of sides of a ring of radius around center
+    pub fn sides(&self, center: Vector3<f32>, radius: f32) -> u32 {
+        let circumference = 2.0 * PI * radius;
+        let sides = match *self {
+            Resolution::Fixed(sides) => sides as f32,
+            Resolution::ByRadius { spacing } => circumference / spacing,
+            Resolution::ByDistance { eye, angle } => circumference / (angle * eye.distance(center)),
+        };
+        // A zero spacing or distance asks for infinitely many sides, which ends up at the maximum
+        (sides.ceil() as u32).clamp(MIN_SIDES, MAX_SIDES)
+    }
+}
+
+/// What happens at the ends of a generalised cylinder
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ends {
+    /// Both ends are left open
+    Open,
+    /// Both ends are closed with a flat cap
+    Capped,
+    /// An end is shared with the cylinder continuing from it, mitred halfway between the two, so
+    /// there is no gap at the joint. Ends that aren't joined to anything are capped
+    Joined,
+}
+
+/// How generalised cylinders are generated, for rendering or export
+#[derive(Clone, Copy, Debug)]
+pub struct CylinderStyle {
+    pub ends: Ends,
+    pub resolution: Resolution,
+}
+
+impl Default for CylinderStyle {
+    fn default() -> CylinderStyle {
+        CylinderStyle::new()
+    }
+}
+
+impl CylinderStyle {
+    pub fn new() -> CylinderStyle {
+        CylinderStyle {
+            ends: Ends::Joined,
+            resolution: Resolution::ByRadius { spacing: 0.005 },
+        }
+    }
+}
+
+/// Returns a unit vector perpendicular to normal. It only depends on normal, so rings lying in
+/// the same plane start at the same point
+fn perpendicular(normal: Vector3<f32>) -> Vector3<f32> {
+    // Cross with the axis the normal is least aligned with, which is never parallel to it
+    let axis = if normal.x.abs() <= normal.y.abs() && normal.x.abs() <= normal.z.abs() {
+        Vector3::unit_x()
+    } else if normal.y.abs() <= normal.z.abs() {
+        Vector3::unit_y()
+    } else {
+        Vector3::unit_z()
+    };
+    normal.cross(axis).normalize()
+}
+
+/// Returns the normal of the plane a joint between two segments is mitred along, which lies
+/// halfway between their directions
+pub fn joint_normal(lower: Vector3<f32>, upper: Vector3<f32>) -> Vector3<f32> {
+    let sum = lower.normalize() + upper.normalize();
+    if sum.magnitude2() > f32::EPSILON {
+        sum.normalize()
+    } else {
+        lower.normalize()
+    }
+}
+
+/// A circle of vertices around a generalised cylinder
+#[derive(Clone, Copy, Debug)]
+pub struct Ring {
+    center: Vector3<f32>,
+    u: Vector3<f32>, // Direction of the first vertex from the center
+    v: Vector3<f32>, // Direction a quarter turn on from the first vertex
+    radius: f32,
+    sides: u32,
+    color: [f32; 4],
+}
+
+impl Ring {
+    /// Creates a ring of radius around center, lying in the plane with the given normal
+    pub fn new(
+        center: Vector3<f32>,
+        normal: Vector3<f32>,
+        radius: f32,
+        color: [f32; 4],
+        resolution: &Resolution,
+    ) -> Ring {
+        let normal = normal.normalize();
+        let u = perpendicular(normal);
+        Ring {
+            center,
+            u,
+            v: normal.cross(u),
+            radius,
+            sides: resolution.sides(center, radius),
+            color,
+        }
+    }
+
+    fn step(&self) -> f32 {
+        2.0 * PI / self.sides as f32
+    }
+
+    fn vertex(&self, index: u32) -> Vertex {
+        let angle = (index % self.sides) as f32 * self.step();
+        let offset = (self.u * angle.cos() + self.v * angle.sin()) * self.radius;
+        Vertex {
+            loc: (self.center + offset).into(),
+            color: self.color,
+        }
+    }
+
+    /// Returns the angle of the first vertex of other around this ring
+    fn phase_of(&self, other: &Ring) -> f32 {
+        other.u.dot(self.v).atan2(other.u.dot(self.u))
+    }
+
+    /// Closes the ring with a fan of triangles around its center
+    fn cap(&self) -> Vec<Vertex> {
+        let center = Vertex {
+            loc: self.center.into(),
+            color: self.color,
+        };
+        let mut vertex_list = Vec::with_capacity(self.sides as usize * 3);
+        for j in 0..self.sides {
+            vertex_list.push(center);
+            vertex_list.push(self.vertex(j));
+            vertex_list.push(self.vertex(j + 1));
+        }
+        vertex_list
+    }
+}
+
+/// Joins two rings with a band of triangles. The rings may have different radii and numbers of
+/// sides, and be turned against each other: vertices are walked around both rings in the order of
+/// their angles, always advancing along the ring whose next vertex comes first
+fn stitch(a: &Ring, b: &Ring) -> Vec<Vertex> {
+    // Start b at the vertex closest to the first vertex of a
+    let phase = a.phase_of(b);
+    let start = (-phase / b.step()).round() as i64;
+    let b_start = start.rem_euclid(b.sides as i64) as u32;
+    let b_angle = phase + start as f32 * b.step();
+
+    let mut vertex_list = Vec::with_capacity((a.sides + b.sides) as usize * 3);
+    let (mut i, mut j) = (0, 0);
+    while i < a.sides || j < b.sides {
+        let next_a = (i + 1) as f32 * a.step();
+        let next_b = b_angle + (j + 1) as f32 * b.step();
+        if j == b.sides || (i < a.sides && next_a <= next_b) {
+            vertex_list.push(a.vertex(i));
+            vertex_list.push(a.vertex(i + 1));
+            vertex_list.push(b.vertex(b_start + j));
+            i += 1;
+        } else {
+            vertex_list.push(a.vertex(i));
+            vertex_list.push(b.vertex(b_start + j + 1));
+            vertex_list.push(b.vertex(b_start + j));
+            j += 1;
+        }
+    }
+    vertex_list
+}
+
+/// Generates a generalised cylinder from the base ring to the tip ring, closing either end with a
+/// cap if asked to
+pub fn tube(base: &Ring, tip: &Ring, cap_base: bool, cap_tip: bool) -> Vec<Vertex> {
+    let mut vertex_list = stitch(base, tip);
+    if cap_base {
+        vertex_list.append(&mut base.cap());
+    }
+    if cap_tip {
+        vertex_list.append(&mut tip.cap());
+    }
+    vertex_list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const WHITE: [f32; 4] = [1.0; 4];
+
+    /// Returns a ring of sides sides around the y axis at height, turned by angle about it
+    fn ring(height: f32, radius: f32, sides: u32, angle: f32) -> Ring {
+        let mut ring = Ring::new(
+            Vector3::new(0.0, height, 0.0),
+            Vector3::unit_y(),
+            radius,
+            WHITE,
+            &Resolution::Fixed(sides),
+        );
+        let (u, v) = (ring.u, ring.v);
+        ring.u = u * angle.cos() + v * angle.sin();
+        ring.v = v * angle.cos() - u * angle.sin();
+        ring
+    }
+
+    /// Returns the edge between two vertices, the same whichever way round they are given
+    fn edge(a: &Vertex, b: &Vertex) -> [[i64; 3]; 2] {
+        let mut edge = [a, b].map(|vertex| vertex.loc.map(|c| (c * 1.0e4).round() as i64));
+        edge.sort();
+        edge
+    }
+
+    /// Counts how often every edge of the triangles in vertices is used
+    fn edge_counts(vertices: &[Vertex]) -> HashMap<[[i64; 3]; 2], u32> {
+        let mut counts = HashMap::new();
+        for triangle in vertices.chunks_exact(3) {
+            for k in 0..3 {
+                *counts
+                    .entry(edge(&triangle[k], &triangle[(k + 1) % 3]))
+                    .or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn stitch_joins_every_side_of_both_rings() {
+        for (a_sides, b_sides, angle) in [(5, 8, 0.3), (8, 5, -2.0), (6, 6, 0.0), (3, 32, 1.0)] {
+            let a = ring(0.0, 1.0, a_sides, 0.0);
+            let b = ring(1.0, 0.5, b_sides, angle);
+            let band = stitch(&a, &b);
+            assert_eq!(band.len() as u32, 3 * (a_sides + b_sides));
+            let counts = edge_counts(&band);
+            for (ring, sides) in [(&a, a_sides), (&b, b_sides)] {
+                for j in 0..sides {
+                    let side = edge(&ring.vertex(j), &ring.vertex(j + 1));
+                    assert_eq!(counts.get(&side), Some(&1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn capped_tube_is_closed() {
+        let tube = tube(&ring(0.0, 1.0, 7, 0.0), &ring(2.0, 0.3, 4, 0.8), true, true);
+        assert!(edge_counts(&tube).values().all(|&count| count == 2));
+    }
+
+    #[test]
+    fn straight_tube_has_the_area_of_a_prism() {
+        let sides = 12;
+        let band = tube(
+            &ring(0.0, 1.0, sides, 0.0),
+            &ring(2.0, 1.0, sides, 0.0),
+            false,
+            false,
+        );
+        let side = 2.0 * (PI / sides as f32).sin();
+        let area = crate::leaf::mesh_area(&band);
+        assert!((area - sides as f32 * side * 2.0).abs() < 1.0e-4);
+    }
+}
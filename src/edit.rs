@@ -0,0 +1,252 @@
+#![allow(dead_code)]
+use cgmath::{Quaternion, Rad, Rotation, Rotation3};
+use std::collections::HashMap;
+
+use super::archetype::*;
+use super::error::*;
+use super::node::*;
+use super::plant::*;
+use super::util::*;
+
+impl NodeBuffer {
+    /// Returns an error unless node_index can be attached to parent, which it can't be if the
+    /// parent is the node itself or one of its descendants
+    fn check_attachable(&self, node_index: u32, parent: u32) -> Result<()> {
+        self.check_live(node_index)?;
+        self.check_live(parent)?;
+        if parent == node_index || self.ancestors(parent).any(|index| index == node_index) {
+            return Err(Error::CyclicAttachment {
+                node: node_index,
+                parent,
+            });
+        }
+        Ok(())
+    }
+
+    /// Grows a new lateral bud part-way along the segment at node_index, by dividing the segment
+    /// at fraction of its length and attaching the bud at the split point, rotated about the
+    /// segment by azimuth. Returns the index of the bud. Nothing changes if there is no room for
+    /// both the upper half of the segment and the bud
+    pub fn insert_lateral(
+        &mut self,
+        node_index: u32,
+        fraction: f32,
+        archetype: &Archetype,
+        azimuth: f32,
+    ) -> Result<u32> {
+        self.check_live(node_index)?;
+        self.reserve(2)?;
+        self.divide(fraction, node_index, SplitChildren::Upper)?;
+        let bud_index = self.try_alloc()?;
+        let plant = self.columns().plant_handle(node_index as usize);
+        self.set(bud_index, lateral_bud(archetype, plant, azimuth));
+        self.add_child(node_index, bud_index);
+        Ok(bud_index)
+    }
+
+    /// Copies node_index and all of its descendants into new nodes, and returns the index of the
+    /// copy of node_index. The copies belong to plant if one is given, and to the same plants as
+    /// the originals otherwise. The copy is the root of its own tree, so its orientation is taken
+    /// relative to the world until it is grafted somewhere. Nothing is copied if there is no room
+    /// for the whole subtree
+    pub fn clone_subtree(&mut self, node_index: u32, plant: Option<PlantHandle>) -> Result<u32> {
+        self.check_live(node_index)?;
+        let originals: Vec<u32> = self.depth_first(node_index).collect();
+        let copies = self.try_alloc_many(originals.len() as u32)?;
+        let mut copy_of = HashMap::with_capacity(originals.len());
+        // Parents come before their children, and children in order, so adding every copy as
+        // the last child of its parent's copy rebuilds the same tree
+        for (&original, &copy) in originals.iter().zip(copies.iter()) {
+            let mut node = self.get(original);
+            node.firstChildIndex = INVALID_INDEX;
+            node.nextSiblingIndex = INVALID_INDEX;
+            node.parentIndex = INVALID_INDEX;
+            if let Some(plant) = plant {
+                node.set_plant(plant);
+            }
+            self.set(copy, node);
+            copy_of.insert(original, copy);
+            if original != node_index {
+                let parent = self.columns().parentIndex[original as usize];
+                self.add_child(copy_of[&parent], copy);
+            }
+        }
+        Ok(copies[0])
+    }
+
+    /// Moves node_index, along with everything growing out of it, to the end of the children of
+    /// new_parent. The orientation of the node is corrected so that it keeps pointing the same way
+    /// in the world, and so does the rest of the subtree. Nodes always grow from the tip of their
+    /// parent, so the whole subtree is moved to start at the tip of new_parent. Nodes keep the
+    /// plant they belong to
+    pub fn reparent(
+        &mut self,
+        node_index: u32,
+        new_parent: u32,
+        plant_buffer: &PlantBuffer,
+    ) -> Result<()> {
+        self.check_attachable(node_index, new_parent)?;
+        self.update_frames(plant_buffer);
+        let world_rotation = self.frame(node_index).rotation;
+        let parent_rotation = self.frame(new_parent).rotation;
+
+        self.detach(node_index);
+        self.add_child(new_parent, node_index);
+        // The world rotation of a node is its parent's times its own, which includes the roll
+        let nodes = self.columns_mut();
+        let i = node_index as usize;
+        nodes.orientation[i] = renormalize(
+            parent_rotation.invert()
+                * world_rotation
+                * Quaternion::from_angle_y(Rad(-nodes.roll[i])),
+        );
+        Ok(())
+    }
+
+    /// Grafts the scion, the subtree at scion_index, onto the stock node. The scion is moved to the
+    /// stock's tip and keeps pointing the same way, see reparent. It becomes part of the stock's
+    /// plant, which feeds it from then on
+    pub fn graft(
+        &mut self,
+        scion_index: u32,
+        stock_index: u32,
+        plant_buffer: &PlantBuffer,
+    ) -> Result<()> {
+        self.reparent(scion_index, stock_index, plant_buffer)?;
+        let plant = self.columns().plant_handle(stock_index as usize);
+        let scion: Vec<u32> = self.depth_first(scion_index).collect();
+        let nodes = self.columns_mut();
+        for index in scion {
+            nodes.plantId[index as usize] = plant.index;
+            nodes.plantGeneration[index as usize] = plant.generation;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use cgmath::{InnerSpace, Vector3};
+
+    /// Returns the vector from base to tip of every node in the subtree at node_index
+    fn spans(node_buffer: &NodeBuffer, node_index: u32) -> Vec<Vector3<f32>> {
+        node_buffer
+            .depth_first(node_index)
+            .map(|index| node_buffer.frame(index).tip - node_buffer.frame(index).base)
+            .collect()
+    }
+
+    #[test]
+    fn lateral_grows_from_the_split_point() {
+        let archetype_table = ArchetypeTable::new();
+        let archetype = archetype_table.get(GROWING_BUD_ARCHETYPE_INDEX).unwrap();
+        let (plant_buffer, plant) = living_plant();
+        let (mut node_buffer, [lower, upper, leaf, bud]) = shoot(plant);
+        node_buffer.update_frames(&plant_buffer);
+        let frame = node_buffer.frame(lower);
+        let split_point = frame.base + (frame.tip - frame.base) * 0.3;
+
+        let lateral = node_buffer
+            .insert_lateral(lower, 0.3, archetype, 1.0)
+            .unwrap();
+        let children: Vec<u32> = node_buffer.children(lower).collect();
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[1], lateral);
+        assert_eq!(
+            node_buffer.children(children[0]).collect::<Vec<_>>(),
+            [upper, leaf, bud]
+        );
+        assert_eq!(
+            node_buffer.get(lateral).archetypeId,
+            GROWING_BUD_ARCHETYPE_INDEX
+        );
+        node_buffer.update_frames(&plant_buffer);
+        assert!((node_buffer.frame(lateral).base - split_point).magnitude() < 1.0e-5);
+        assert_eq!(node_buffer.validate(), []);
+
+        let size = node_buffer.current_size();
+        assert_eq!(
+            node_buffer.insert_lateral(lower, 1.0, archetype, 1.0),
+            Err(Error::InvalidFraction)
+        );
+        assert_eq!(node_buffer.current_size(), size);
+    }
+
+    #[test]
+    fn reparented_node_keeps_pointing_the_same_way() {
+        let (plant_buffer, plant) = living_plant();
+        let (mut node_buffer, [lower, upper, _, bud]) = shoot(plant);
+        assert_eq!(
+            node_buffer.reparent(lower, upper, &plant_buffer),
+            Err(Error::CyclicAttachment {
+                node: lower,
+                parent: upper
+            })
+        );
+        assert_eq!(
+            node_buffer.reparent(lower, lower, &plant_buffer),
+            Err(Error::CyclicAttachment {
+                node: lower,
+                parent: lower
+            })
+        );
+
+        node_buffer.update_frames(&plant_buffer);
+        let before = spans(&node_buffer, bud);
+        node_buffer.reparent(bud, upper, &plant_buffer).unwrap();
+        assert_eq!(node_buffer.children(upper).collect::<Vec<_>>(), [bud]);
+        assert!(!node_buffer.children(lower).any(|child| child == bud));
+        node_buffer.update_frames(&plant_buffer);
+        let base = node_buffer.frame(bud).base;
+        assert!((base - node_buffer.frame(upper).tip).magnitude() < 1.0e-5);
+        for (after, before) in spans(&node_buffer, bud).iter().zip(&before) {
+            assert!((after - before).magnitude() < 1.0e-5);
+        }
+        assert_eq!(node_buffer.validate(), []);
+    }
+
+    #[test]
+    fn cloned_subtree_grafts_onto_another_plant() {
+        let (plant_buffer, [donor, stock_plant]) =
+            living_plants([[0.0, 0.0, 0.0], [2.0, 0.0, 0.0]]);
+        let mut node_buffer = NodeBuffer::new(8);
+        let donor_root = stem(&mut node_buffer, donor, 2);
+        let branch = organ(&mut node_buffer, donor, STEM_ARCHETYPE_INDEX, 0.5);
+        let leaf = organ(&mut node_buffer, donor, LEAF_ARCHETYPE_INDEX, -1.0);
+        node_buffer.add_child(donor_root, branch);
+        node_buffer.add_child(branch, leaf);
+        let stock_root = stem(&mut node_buffer, stock_plant, 3);
+        let stock = node_buffer.depth_first(stock_root).last().unwrap();
+
+        let scion = node_buffer.clone_subtree(branch, None).unwrap();
+        assert_eq!(node_buffer.roots().count(), 3);
+        node_buffer.update_frames(&plant_buffer);
+        let before = spans(&node_buffer, scion);
+
+        node_buffer.graft(scion, stock, &plant_buffer).unwrap();
+        assert_eq!(node_buffer.validate(), []);
+        assert_eq!(plant_buffer.validate(), []);
+        assert_eq!(node_buffer.roots().count(), 2);
+        for index in node_buffer.depth_first(scion) {
+            assert_eq!(
+                node_buffer.columns().plant_handle(index as usize),
+                stock_plant
+            );
+        }
+        // The originals stay on the donor
+        assert_eq!(node_buffer.depth_first(branch).count(), 2);
+        for index in node_buffer.depth_first(donor_root) {
+            assert_eq!(node_buffer.columns().plant_handle(index as usize), donor);
+        }
+
+        // The scion now grows from the tip of the stock, pointing the same way as before
+        node_buffer.update_frames(&plant_buffer);
+        let base = node_buffer.frame(scion).base;
+        assert!((base - node_buffer.frame(stock).tip).magnitude() < 1.0e-5);
+        for (after, before) in spans(&node_buffer, scion).iter().zip(&before) {
+            assert!((after - before).magnitude() < 1.0e-5);
+        }
+    }
+}
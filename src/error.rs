@@ -0,0 +1,57 @@
+use std::fmt;
+
+/// Errors that can occur while manipulating the simulation buffers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Every slot in the node buffer is in use
+    NodeBufferFull,
+    /// Every slot in the plant buffer is in use
+    PlantBufferFull,
+    /// A slot was freed while the free stack already held every slot, meaning that some slot has
+    /// been freed twice
+    FreeStackFull,
+    /// The slot was freed while it was already garbage
+    DoubleFree(u32),
+    /// The index does not refer to a slot in the buffer
+    InvalidIndex(u32),
+    /// The handle refers to an entry that has been freed since the handle was made
+    StaleHandle,
+    /// The coordinates lie outside of the grid
+    GridOutOfBounds { x: u32, y: u32, z: u32 },
+    /// A node refers to an archetype that is not in the archetype table
+    UnknownArchetype(u32),
+    /// The node has been freed
+    GarbageNode(u32),
+    /// The node can't be attached to the parent, because the parent grows out of it
+    CyclicAttachment { node: u32, parent: u32 },
+    /// A segment can only be split at a fraction of its length strictly between zero and one
+    InvalidFraction,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NodeBufferFull => write!(f, "No Memory Left In NodeBuffer"),
+            Error::PlantBufferFull => write!(f, "No Memory Left In PlantBuffer"),
+            Error::FreeStackFull => write!(f, "Free Stack Full (This should not happen)"),
+            Error::DoubleFree(index) => write!(f, "Slot {} freed twice", index),
+            Error::InvalidIndex(index) => write!(f, "Invalid index {}", index),
+            Error::StaleHandle => write!(f, "Stale handle"),
+            Error::GridOutOfBounds { x, y, z } => {
+                write!(f, "Grid coordinates ({}, {}, {}) out of bounds", x, y, z)
+            }
+            Error::UnknownArchetype(id) => write!(f, "Unknown archetype {}", id),
+            Error::GarbageNode(index) => write!(f, "Node {} has been freed", index),
+            Error::CyclicAttachment { node, parent } => write!(
+                f,
+                "Node {} can't be attached to node {}, which grows out of it",
+                node, parent
+            ),
+            Error::InvalidFraction => write!(f, "Split fraction must lie between 0 and 1"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
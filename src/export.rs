@@ -0,0 +1,21 @@
+#![allow(dead_code)]
+use std::io::{self, Write};
+
+use super::vertex::mVertex as Vertex;
+
+/// Writes a list of triangles as a Wavefront OBJ mesh. Every vertex is written with its colour
+/// after its position, which most tools read as a vertex colour
+pub fn write_obj(vertices: &[Vertex], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "# {} triangles", vertices.len() / 3)?;
+    for vertex in vertices {
+        let [x, y, z] = vertex.loc;
+        let [r, g, b, _] = vertex.color;
+        writeln!(out, "v {} {} {} {} {} {}", x, y, z, r, g, b)?;
+    }
+    // OBJ indices start at one
+    for triangle in 0..vertices.len() / 3 {
+        let first = triangle * 3 + 1;
+        writeln!(out, "f {} {} {}", first, first + 1, first + 2)?;
+    }
+    out.flush()
+}
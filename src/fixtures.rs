@@ -0,0 +1,76 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+//! Small plants for the tests of every module to build on
+use cgmath::{Quaternion, Rad, Rotation3};
+
+use super::archetype::*;
+use super::node::*;
+use super::plant::*;
+
+/// Returns a plant buffer holding a living plant at each of locations, and handles to the plants
+pub fn living_plants<const N: usize>(locations: [[f32; 3]; N]) -> (PlantBuffer, [PlantHandle; N]) {
+    let mut plant_buffer = PlantBuffer::new(N.max(1) as u32);
+    let plants = locations.map(|location| {
+        let mut plant = Plant::new();
+        plant.status = STATUS_ALIVE;
+        plant.location = location;
+        plant_buffer.alloc_insert(plant)
+    });
+    (plant_buffer, plants)
+}
+
+/// Returns a plant buffer holding a single living plant at the origin, and a handle to the plant
+pub fn living_plant() -> (PlantBuffer, PlantHandle) {
+    let (plant_buffer, [plant]) = living_plants([[0.0, 0.0, 0.0]]);
+    (plant_buffer, plant)
+}
+
+/// Adds a living node of archetypeId and plant, a unit long and tilted by angle about the z
+/// axis, and returns its index
+pub fn organ(
+    node_buffer: &mut NodeBuffer,
+    plant: PlantHandle,
+    archetypeId: u32,
+    angle: f32,
+) -> u32 {
+    let mut node = Node::new();
+    node.status = STATUS_ALIVE;
+    node.visible = 1;
+    node.archetypeId = archetypeId;
+    node.set_plant(plant);
+    node.length = 1.0;
+    node.radius = 0.1;
+    node.volume = cylinder_volume(node);
+    node.set_orientation(Quaternion::from_angle_z(Rad(angle)));
+    let index = node_buffer.alloc();
+    node_buffer.set(index, node);
+    index
+}
+
+/// Adds a stem of plant made of segments straight segments, each the first child of the one
+/// below it, and returns the index of the lowest one
+pub fn stem(node_buffer: &mut NodeBuffer, plant: PlantHandle, segments: u32) -> u32 {
+    let root = organ(node_buffer, plant, STEM_ARCHETYPE_INDEX, 0.0);
+    let mut parent = root;
+    for _ in 1..segments {
+        let child = organ(node_buffer, plant, STEM_ARCHETYPE_INDEX, 0.0);
+        node_buffer.add_child(parent, child);
+        parent = child;
+    }
+    root
+}
+
+/// Builds a stem segment whose first child is another stem segment, followed by a leaf and a
+/// bud. Returns the buffer and the indices of the lower stem, the upper stem, the leaf and the
+/// bud
+pub fn shoot(plant: PlantHandle) -> (NodeBuffer, [u32; 4]) {
+    let mut node_buffer = NodeBuffer::new(4);
+    let lower = organ(&mut node_buffer, plant, STEM_ARCHETYPE_INDEX, 0.1);
+    let upper = organ(&mut node_buffer, plant, STEM_ARCHETYPE_INDEX, 0.2);
+    let leaf = organ(&mut node_buffer, plant, LEAF_ARCHETYPE_INDEX, 1.0);
+    let bud = organ(&mut node_buffer, plant, GROWING_BUD_ARCHETYPE_INDEX, -1.0);
+    for child in [upper, leaf, bud] {
+        node_buffer.add_child(lower, child);
+    }
+    (node_buffer, [lower, upper, leaf, bud])
+}
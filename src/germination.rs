@@ -0,0 +1,216 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+use cgmath::{Quaternion, Rad, Rotation3};
+use std::f32::consts::PI;
+
+use super::archetype::*;
+use super::error::*;
+use super::grid::*;
+use super::node::*;
+use super::plant::*;
+
+/// Wakes up the dormant seed at plant_index, allocating a root growing down into the soil and a
+/// shoot bud growing up from the plant's location. The plant's age restarts at zero. If there is
+/// no room for both nodes, the seed stays dormant and an error is returned
+pub fn germinate(
+    node_buffer: &mut NodeBuffer,
+    plant_buffer: &mut PlantBuffer,
+    plant_index: u32,
+) -> Result<()> {
+    let indices = node_buffer.try_alloc_many(2)?;
+
+    let mut plant = plant_buffer.get(plant_index);
+    plant.status = STATUS_ALIVE;
+    plant.age = 0;
+    plant_buffer.set(plant_index, plant);
+
+    let rootindex = indices[0];
+    let mut root = Node::new();
+    root.status = STATUS_ALIVE;
+    root.archetypeId = ROOT_ARCHETYPE_INDEX;
+    root.visible = 1;
+    root.set_plant(plant_buffer.handle(plant_index));
+    root.length = 0.01;
+    root.radius = 0.002;
+    // Turn the root upside down so that it grows into the soil
+    root.set_orientation(Quaternion::from_angle_z(Rad(PI)));
+    root.volume = cylinder_volume(root);
+    node_buffer.set(rootindex, root);
+
+    let shootindex = indices[1];
+    let mut shoot = Node::new();
+    shoot.status = STATUS_ALIVE;
+    shoot.archetypeId = GROWING_BUD_ARCHETYPE_INDEX;
+    shoot.visible = 1;
+    shoot.set_plant(plant_buffer.handle(plant_index));
+    shoot.length = 0.05;
+    shoot.radius = 0.01;
+    shoot.volume = cylinder_volume(shoot);
+    node_buffer.set(shootindex, shoot);
+    Ok(())
+}
+
+/// Gives every dormant seed a chance to germinate. A seed may only germinate once the mean
+/// moisture and temperature of the cells around it reach the seed archetype's thresholds; from
+/// then on it germinates with a constant probability per tick. Seeds that stay dormant for longer
+/// than the dormancy limit lose their viability and are freed. Stops with an error once there is
+/// no room left for germinating seeds
+pub fn germinate_seeds(
+    node_buffer: &mut NodeBuffer,
+    plant_buffer: &mut PlantBuffer,
+    grid_buffer: &GridBuffer,
+    archetype_table: &ArchetypeTable,
+) -> Result<()> {
+    let seed = archetype_table
+        .get(SEED_ARCHETYPE_INDEX)
+        .ok_or(Error::UnknownArchetype(SEED_ARCHETYPE_INDEX))?;
+
+    for pi in 0..plant_buffer.size() {
+        let plant = plant_buffer.get(pi);
+        if plant.status != STATUS_DORMANT {
+            continue;
+        }
+
+        if plant.age > seed.dormancyLimit {
+            plant_buffer.free(pi);
+            continue;
+        }
+
+        if let Some((moisture, temperature)) = grid_buffer.conditions_beneath(plant.location) {
+            if moisture >= seed.germinationMoisture as f32
+                && temperature >= seed.germinationTemperature as f32
+                && rand::random::<f32>() < seed.germinationProbability
+            {
+                germinate(node_buffer, plant_buffer, pi)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reproduction::sow;
+
+    /// Returns a grid of moist, warm soil with a layer of air above it
+    fn soil() -> GridBuffer {
+        let mut grid_buffer = GridBuffer::new(3, 2, 3);
+        for x in 0..3 {
+            for z in 0..3 {
+                let mut cell = GridCell::new();
+                cell.typeCode = GRIDCELL_TYPE_SOIL;
+                cell.moisture = 40;
+                cell.temperature = 20;
+                grid_buffer.set(x, 0, z, cell);
+                cell.typeCode = GRIDCELL_TYPE_AIR;
+                grid_buffer.set(x, 1, z, cell);
+            }
+        }
+        grid_buffer
+    }
+
+    /// Returns a table whose seeds germinate as soon as the cells around them are moist and warm
+    /// enough
+    fn archetypes(moisture: u32) -> ArchetypeTable {
+        let mut archetype_table = ArchetypeTable::new();
+        let mut seed = archetype_table.get(SEED_ARCHETYPE_INDEX).unwrap().clone();
+        seed.germinationMoisture = moisture;
+        seed.germinationTemperature = 10;
+        seed.germinationProbability = 1.0;
+        seed.dormancyLimit = 100;
+        archetype_table.set(SEED_ARCHETYPE_INDEX, seed);
+        archetype_table
+    }
+
+    fn seed(plant_buffer: &mut PlantBuffer, age: u32) -> u32 {
+        let index = sow(plant_buffer, [1.5, 1.0, 1.5], 1.0).unwrap();
+        let mut plant = plant_buffer.get(index);
+        plant.age = age;
+        plant_buffer.set(index, plant);
+        index
+    }
+
+    #[test]
+    fn seed_grows_a_root_and_a_shoot() {
+        let mut plant_buffer = PlantBuffer::new(1);
+        let index = seed(&mut plant_buffer, 50);
+        let mut node_buffer = NodeBuffer::new(2);
+
+        germinate_seeds(
+            &mut node_buffer,
+            &mut plant_buffer,
+            &soil(),
+            &archetypes(30),
+        )
+        .unwrap();
+        let plant = plant_buffer.get(index);
+        assert_eq!(plant.status, STATUS_ALIVE);
+        assert_eq!(plant.age, 0);
+        let mut archetypes: Vec<u32> = node_buffer
+            .live_indices()
+            .map(|ni| node_buffer.get(ni))
+            .inspect(|node| assert_eq!(node.plantId, index))
+            .map(|node| node.archetypeId)
+            .collect();
+        archetypes.sort();
+        assert_eq!(
+            archetypes,
+            [ROOT_ARCHETYPE_INDEX, GROWING_BUD_ARCHETYPE_INDEX]
+        );
+        assert!(node_buffer.validate().is_empty());
+    }
+
+    #[test]
+    fn seed_waits_for_moisture() {
+        let mut plant_buffer = PlantBuffer::new(1);
+        let index = seed(&mut plant_buffer, 50);
+        let mut node_buffer = NodeBuffer::new(2);
+
+        germinate_seeds(
+            &mut node_buffer,
+            &mut plant_buffer,
+            &soil(),
+            &archetypes(50),
+        )
+        .unwrap();
+        assert_eq!(plant_buffer.get(index).status, STATUS_DORMANT);
+        assert_eq!(node_buffer.live_indices().count(), 0);
+    }
+
+    #[test]
+    fn seed_past_its_dormancy_limit_is_freed() {
+        let mut plant_buffer = PlantBuffer::new(1);
+        let index = seed(&mut plant_buffer, 101);
+        let mut node_buffer = NodeBuffer::new(2);
+
+        germinate_seeds(
+            &mut node_buffer,
+            &mut plant_buffer,
+            &soil(),
+            &archetypes(30),
+        )
+        .unwrap();
+        assert!(plant_buffer.is_garbage(index));
+        assert_eq!(node_buffer.live_indices().count(), 0);
+    }
+
+    #[test]
+    fn seed_stays_dormant_without_room_for_its_nodes() {
+        let mut plant_buffer = PlantBuffer::new(1);
+        let index = seed(&mut plant_buffer, 50);
+        let mut node_buffer = NodeBuffer::with_max_size(1, 1);
+
+        assert_eq!(
+            germinate_seeds(
+                &mut node_buffer,
+                &mut plant_buffer,
+                &soil(),
+                &archetypes(30),
+            ),
+            Err(Error::NodeBufferFull)
+        );
+        assert_eq!(plant_buffer.get(index).status, STATUS_DORMANT);
+        assert_eq!(node_buffer.live_indices().count(), 0);
+    }
+}
@@ -4,7 +4,10 @@
 #![allow(non_snake_case)]
 use cgmath::{Matrix4, Rad, Transform, Vector3, Vector4};
 
+use super::color::*;
+use super::error::*;
 use super::vertex::mVertex as Vertex;
+use std::fmt;
 use std::sync::Arc;
 
 pub const GRIDCELL_TYPE_INVALID_MATERIAL: u32 = 0;
@@ -19,6 +22,7 @@ pub struct GridBuffer {
     xsize: u32,
     ysize: u32,
     zsize: u32,
+    version: u64, // Counts visible changes to the cells, so meshes know when to rebuild
 }
 
 impl GridBuffer {
@@ -40,80 +44,319 @@ impl GridBuffer {
             ysize,
             zsize,
             grid_cells: vec![GridCell::new(); (xsize * ysize * zsize) as usize],
+            version: 0,
         }
     }
 
+    /// Returns a number that changes whenever a cell of the grid changes in a way that can change
+    /// how the grid is drawn, see GridCell::looks_like
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
     fn toId(&self, x: u32, y: u32, z: u32) -> usize {
         (self.ysize * self.xsize * z + self.xsize * y + x) as usize
     }
 
     pub fn get(&self, x: u32, y: u32, z: u32) -> GridCell {
-        self.grid_cells[self.toId(x, y, z)].clone()
+        match self.try_get(x, y, z) {
+            Ok(cell) => cell,
+            Err(e) => panic!("{}", e),
+        }
     }
 
     pub fn set(&mut self, x: u32, y: u32, z: u32, cell: GridCell) {
+        if let Err(e) = self.try_set(x, y, z, cell) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Returns an error if x, y, z lies outside of the grid
+    fn check_bounds(&self, x: u32, y: u32, z: u32) -> Result<()> {
+        if x >= self.xsize || y >= self.ysize || z >= self.zsize {
+            Err(Error::GridOutOfBounds { x, y, z })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the cell at x, y, z, or an error if it lies outside of the grid
+    pub fn try_get(&self, x: u32, y: u32, z: u32) -> Result<GridCell> {
+        self.check_bounds(x, y, z)?;
+        Ok(self.grid_cells[self.toId(x, y, z)].clone())
+    }
+
+    /// Sets the cell at x, y, z, or returns an error if it lies outside of the grid
+    pub fn try_set(&mut self, x: u32, y: u32, z: u32, cell: GridCell) -> Result<()> {
+        self.check_bounds(x, y, z)?;
         let id = self.toId(x, y, z);
-        self.grid_cells[id] = cell.clone();
+        if !self.grid_cells[id].looks_like(&cell) {
+            self.version += 1;
+        }
+        self.grid_cells[id] = cell;
+        Ok(())
+    }
+
+    /// Returns the cell at x, y, z (which must lie within the grid) and all of its neighbours that
+    /// lie within the grid as well
+    pub fn neighbourhood(&self, x: u32, y: u32, z: u32) -> Vec<GridCell> {
+        let mut cells = Vec::with_capacity(27);
+        for nx in x.saturating_sub(1)..=(x + 1).min(self.xsize - 1) {
+            for ny in y.saturating_sub(1)..=(y + 1).min(self.ysize - 1) {
+                for nz in z.saturating_sub(1)..=(z + 1).min(self.zsize - 1) {
+                    cells.push(self.get(nx, ny, nz));
+                }
+            }
+        }
+        cells
     }
 
-    fn gen_vertex_cell(&self, x: u32, y: u32, z: u32) -> Vec<Vertex> {
-        if self.get(x, y, z).typeCode != GRIDCELL_TYPE_SOIL {
-            return vec![];
+    /// Returns the coordinates of the cell directly beneath location, or None if it lies outside
+    /// the grid. For anything resting on the soil surface this is the top soil cell
+    pub fn cell_beneath(&self, location: [f32; 3]) -> Option<(u32, u32, u32)> {
+        let x = location[0];
+        let y = location[1] - 0.5;
+        let z = location[2];
+        if x < 0.0
+            || y < 0.0
+            || z < 0.0
+            || x as u32 >= self.xsize
+            || y as u32 >= self.ysize
+            || z as u32 >= self.zsize
+        {
+            return None;
         }
+        Some((x as u32, y as u32, z as u32))
+    }
 
-        let lbu = Vertex {
-            loc: [x as f32, y as f32, z as f32],
-            color: [0.5, 0.9, 0.5, 1.0],
-        };
-        let rbu = Vertex {
-            loc: [(x + 1) as f32, y as f32, z as f32],
-            color: [0.5, 0.5, 0.9, 1.0],
-        };
-        let lfu = Vertex {
-            loc: [x as f32, y as f32, (z + 1) as f32],
-            color: [0.9, 0.5, 0.5, 1.0],
-        };
-        let rfu = Vertex {
-            loc: [(x + 1) as f32, y as f32, (z + 1) as f32],
-            color: [0.5, 0.9, 0.5, 1.0],
-        };
-        let lbl = Vertex {
-            loc: [x as f32, (y + 1) as f32, z as f32],
-            color: [0.5, 0.5, 0.9, 1.0],
-        };
-        let rbl = Vertex {
-            loc: [(x + 1) as f32, (y + 1) as f32, z as f32],
-            color: [0.9, 0.5, 0.5, 1.0],
-        };
-        let lfl = Vertex {
-            loc: [x as f32, (y + 1) as f32, (z + 1) as f32],
-            color: [0.5, 0.5, 0.5, 1.0],
-        };
-        let rfl = Vertex {
-            loc: [(x + 1) as f32, (y + 1) as f32, (z + 1) as f32],
-            color: [0.5, 0.5, 0.5, 1.0],
-        };
+    /// Returns the mean moisture and temperature of the cell directly beneath location and its
+    /// neighbours, or None if location is outside the grid
+    pub fn conditions_beneath(&self, location: [f32; 3]) -> Option<(f32, f32)> {
+        let (x, y, z) = self.cell_beneath(location)?;
+        let cells = self.neighbourhood(x, y, z);
+        let count = cells.len() as f32;
+        let moisture = cells.iter().map(|c| c.moisture as f32).sum::<f32>() / count;
+        let temperature = cells.iter().map(|c| c.temperature as f32).sum::<f32>() / count;
+        Some((moisture, temperature))
+    }
 
-        vec![
-            lbu, rbu, lfu, lfu, rfu, rbu, // upper square
-            lbl, rbl, lfl, lfl, rfl, rbl, // lower square
-            lfu, rfu, lfl, lfl, rfl, rfu, // front square
-            lbu, rbu, lbl, lbl, rbl, rbu, // back square
-            lbu, lfu, lbl, lbl, lfl, lfu, // left square
-            rbu, rfu, rbl, rbl, rfl, rfu, // right square
-        ]
+    /// Returns the height of the top of the highest soil cell in the column at x, z, or None if
+    /// the column is outside the grid or contains no soil
+    pub fn soil_surface(&self, x: u32, z: u32) -> Option<u32> {
+        if x >= self.xsize || z >= self.zsize {
+            return None;
+        }
+        (0..self.ysize)
+            .rev()
+            .find(|&y| self.get(x, y, z).typeCode == GRIDCELL_TYPE_SOIL)
+            .map(|y| y + 1)
     }
 
-    pub fn gen_vertex(&self) -> Vec<Vertex> {
-        let mut vertex_list: Vec<Vertex> = Vec::new();
+    /// Returns the smallest and largest values of field over the cells view draws, or None if it
+    /// draws no cells
+    pub fn field_range(&self, field: Field, view: &GridView) -> Option<(f32, f32)> {
+        let mut range = None;
         for x in 0..self.xsize {
             for y in 0..self.ysize {
                 for z in 0..self.zsize {
-                    vertex_list.append(&mut self.gen_vertex_cell(x, y, z));
+                    let cell = self.get(x, y, z);
+                    if !view.shows(&cell, x, y, z) {
+                        continue;
+                    }
+                    let value = field.value(&cell);
+                    range = match range {
+                        None => Some((value, value)),
+                        Some((min, max)) => Some((f32::min(min, value), f32::max(max, value))),
+                    };
                 }
             }
         }
-        vertex_list
+        range
+    }
+}
+
+/// Returns the colour cells of the material typeCode are drawn in, see CellColors::Material
+pub fn material_color(typeCode: u32) -> [f32; 4] {
+    match typeCode {
+        GRIDCELL_TYPE_AIR => [0.5, 0.5, 0.9, 1.0],
+        GRIDCELL_TYPE_WATER => [0.5, 0.9, 0.9, 1.0],
+        GRIDCELL_TYPE_SOIL => [0.9, 0.7, 0.5, 1.0],
+        _ => [0.5, 0.5, 0.5, 1.0],
+    }
+}
+
+/// A quantity grid cells can be coloured by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Temperature,
+    Moisture,
+    Sunlight,
+    PlantDensity,
+}
+
+impl Field {
+    pub const ALL: [Field; 4] = [
+        Field::Temperature,
+        Field::Moisture,
+        Field::Sunlight,
+        Field::PlantDensity,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Temperature => "temperature",
+            Field::Moisture => "moisture",
+            Field::Sunlight => "sunlight",
+            Field::PlantDensity => "plant density",
+        }
+    }
+
+    pub fn value(&self, cell: &GridCell) -> f32 {
+        match self {
+            Field::Temperature => cell.temperature as f32,
+            Field::Moisture => cell.moisture as f32,
+            Field::Sunlight => cell.sunlight as f32,
+            Field::PlantDensity => cell.plantDensity as f32,
+        }
+    }
+}
+
+/// How gen_vertex colours cells
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CellColors {
+    /// In a flat pastel shade for each material, see material_color
+    Material,
+    /// By the value of field, mapped linearly from range onto map. Without a range, the map is
+    /// stretched from the smallest to the largest value among the cells drawn
+    Field {
+        field: Field,
+        map: ColorMap,
+        range: Option<(f32, f32)>,
+    },
+}
+
+/// An axis of the grid
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+impl Axis {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Axis::X => "x",
+            Axis::Y => "y",
+            Axis::Z => "z",
+        }
+    }
+}
+
+/// A layer of cells one cell thick, lying across axis at position along it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Slice {
+    pub axis: Axis,
+    pub position: u32,
+}
+
+impl Slice {
+    /// Returns true if the cell at x, y, z lies in the slice
+    pub fn contains(&self, x: u32, y: u32, z: u32) -> bool {
+        let coordinate = match self.axis {
+            Axis::X => x,
+            Axis::Y => y,
+            Axis::Z => z,
+        };
+        coordinate == self.position
+    }
+
+    /// Returns the slice moved by delta cells along its axis, kept within grid_buffer
+    pub fn moved(&self, delta: i32, grid_buffer: &GridBuffer) -> Slice {
+        let size = match self.axis {
+            Axis::X => grid_buffer.xsize(),
+            Axis::Y => grid_buffer.ysize(),
+            Axis::Z => grid_buffer.zsize(),
+        };
+        let position = self.position.saturating_add_signed(delta);
+        Slice {
+            axis: self.axis,
+            position: position.min(size.saturating_sub(1)),
+        }
+    }
+}
+
+/// Which cells of the grid gen_vertex draws, and how they are coloured
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GridView {
+    pub colors: CellColors,
+    pub slice: Option<Slice>, // Only cells in the slice are drawn, if set
+    pub showAir: bool,
+    pub showSoil: bool,
+}
+
+impl Default for GridView {
+    fn default() -> GridView {
+        GridView::new()
+    }
+}
+
+impl GridView {
+    /// Draws the soil in its material colours, leaving out the air
+    pub fn new() -> GridView {
+        GridView {
+            colors: CellColors::Material,
+            slice: None,
+            showAir: false,
+            showSoil: true,
+        }
+    }
+
+    /// Returns true if cell, which lies at x, y, z, is drawn
+    pub fn shows(&self, cell: &GridCell, x: u32, y: u32, z: u32) -> bool {
+        let material = match cell.typeCode {
+            GRIDCELL_TYPE_AIR => self.showAir,
+            GRIDCELL_TYPE_SOIL => self.showSoil,
+            _ => false,
+        };
+        material
+            && match self.slice {
+                Some(slice) => slice.contains(x, y, z),
+                None => true,
+            }
+    }
+
+    /// Returns the view with a range filled in for field colours that have none, see CellColors
+    pub fn fitted(&self, grid_buffer: &GridBuffer) -> GridView {
+        let mut view = *self;
+        if let CellColors::Field {
+            field,
+            range: range @ None,
+            ..
+        } = &mut view.colors
+        {
+            *range = grid_buffer.field_range(*field, self);
+        }
+        view
+    }
+}
+
+impl fmt::Display for GridView {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.colors {
+            CellColors::Material => write!(f, "material colours")?,
+            CellColors::Field { field, map, range } => {
+                write!(f, "{}", field.name())?;
+                if let Some((min, max)) = range {
+                    write!(f, " from {} to {}", min, max)?;
+                }
+                write!(f, " ({})", map.name())?;
+            }
+        }
+        if let Some(slice) = self.slice {
+            write!(f, ", slice {} = {}", slice.axis.name(), slice.position)?;
+        }
+        Ok(())
     }
 }
 
@@ -125,6 +368,7 @@ pub struct GridCell {
     pub sunlight: u32,
     pub gravity: u32,
     pub plantDensity: u32,
+    pub organicMatter: u32, // Decomposed plant material in cubic millimetres
 }
 
 impl GridCell {
@@ -136,7 +380,146 @@ impl GridCell {
             sunlight: 0,
             gravity: 0,
             plantDensity: 0,
+            organicMatter: 0,
         }
     }
 
+    /// Returns true if the cell is drawn the same as other however the grid is drawn: both are of
+    /// the same material and hold the same value of every field cells can be coloured by
+    pub fn looks_like(&self, other: &GridCell) -> bool {
+        self.typeCode == other.typeCode
+            && Field::ALL
+                .iter()
+                .all(|field| field.value(self) == field.value(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(typeCode: u32, moisture: u32) -> GridCell {
+        let mut cell = GridCell::new();
+        cell.typeCode = typeCode;
+        cell.moisture = moisture;
+        cell
+    }
+
+    /// Returns a 2x3x2 grid with soil in the bottom two layers and air above, whose moisture is
+    /// ten times the height of the cell
+    fn grid() -> GridBuffer {
+        let mut grid_buffer = GridBuffer::new(2, 3, 2);
+        for x in 0..2 {
+            for y in 0..3 {
+                for z in 0..2 {
+                    let material = match y {
+                        2 => GRIDCELL_TYPE_AIR,
+                        _ => GRIDCELL_TYPE_SOIL,
+                    };
+                    grid_buffer.set(x, y, z, cell(material, 10 * y));
+                }
+            }
+        }
+        grid_buffer
+    }
+
+    #[test]
+    fn view_shows_the_chosen_materials() {
+        let air = cell(GRIDCELL_TYPE_AIR, 0);
+        let soil = cell(GRIDCELL_TYPE_SOIL, 0);
+        let stone = cell(GRIDCELL_TYPE_STONE, 0);
+        let mut view = GridView::new();
+        assert!(view.shows(&soil, 0, 0, 0));
+        assert!(!view.shows(&air, 0, 0, 0));
+        assert!(!view.shows(&stone, 0, 0, 0));
+        view.showAir = true;
+        view.showSoil = false;
+        assert!(view.shows(&air, 0, 0, 0));
+        assert!(!view.shows(&soil, 0, 0, 0));
+    }
+
+    #[test]
+    fn slice_limits_the_view_to_one_layer() {
+        let slice = Slice {
+            axis: Axis::Z,
+            position: 1,
+        };
+        assert!(slice.contains(5, 7, 1));
+        assert!(!slice.contains(1, 1, 0));
+        let view = GridView {
+            slice: Some(slice),
+            ..GridView::new()
+        };
+        let soil = cell(GRIDCELL_TYPE_SOIL, 0);
+        assert!(view.shows(&soil, 0, 0, 1));
+        assert!(!view.shows(&soil, 1, 0, 0));
+    }
+
+    #[test]
+    fn moved_slice_stays_within_the_grid() {
+        let grid_buffer = grid();
+        let slice = Slice {
+            axis: Axis::Y,
+            position: 1,
+        };
+        assert_eq!(slice.moved(1, &grid_buffer).position, 2);
+        assert_eq!(slice.moved(5, &grid_buffer).position, 2);
+        assert_eq!(slice.moved(-5, &grid_buffer).position, 0);
+        assert_eq!(slice.moved(-1, &grid_buffer).axis, Axis::Y);
+    }
+
+    #[test]
+    fn fitted_view_spans_the_cells_it_shows() {
+        let grid_buffer = grid();
+        let colors = |range| CellColors::Field {
+            field: Field::Moisture,
+            map: ColorMap::Viridis,
+            range,
+        };
+        let view = GridView {
+            colors: colors(None),
+            ..GridView::new()
+        };
+        assert_eq!(view.fitted(&grid_buffer).colors, colors(Some((0.0, 10.0))));
+        let everything = GridView {
+            showAir: true,
+            ..view
+        };
+        assert_eq!(
+            everything.fitted(&grid_buffer).colors,
+            colors(Some((0.0, 20.0)))
+        );
+        // Ranges that were given are kept, and a view showing nothing has no range
+        let given = GridView {
+            colors: colors(Some((5.0, 6.0))),
+            ..view
+        };
+        assert_eq!(given.fitted(&grid_buffer), given);
+        let nothing = GridView {
+            showSoil: false,
+            ..view
+        };
+        assert_eq!(nothing.fitted(&grid_buffer).colors, colors(None));
+    }
+
+    #[test]
+    fn view_describes_itself() {
+        let view = GridView {
+            colors: CellColors::Field {
+                field: Field::PlantDensity,
+                map: ColorMap::Grayscale,
+                range: Some((0.0, 4.0)),
+            },
+            slice: Some(Slice {
+                axis: Axis::X,
+                position: 3,
+            }),
+            ..GridView::new()
+        };
+        assert_eq!(
+            view.to_string(),
+            "plant density from 0 to 4 (grayscale), slice x = 3"
+        );
+        assert_eq!(GridView::new().to_string(), "material colours");
+    }
 }
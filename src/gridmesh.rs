@@ -0,0 +1,399 @@
+#![allow(dead_code)]
+use super::grid::*;
+use super::vertex::mVertex as Vertex;
+
+/// Returns what decides whether the faces of the cell at pos can be merged with those of its
+/// neighbours, or None if view doesn't draw it. Faces merge if their keys are equal, which they
+/// are if the cells are drawn in the same colour: cells in material colours must be of the same
+/// material, and cells coloured by a field must hold the same value, whose bits make up the key
+fn cell_key(grid_buffer: &GridBuffer, view: &GridView, pos: [u32; 3]) -> Option<u32> {
+    let cell = grid_buffer.get(pos[0], pos[1], pos[2]);
+    if !view.shows(&cell, pos[0], pos[1], pos[2]) {
+        return None;
+    }
+    match view.colors {
+        CellColors::Material => Some(cell.typeCode),
+        CellColors::Field { field, .. } => Some(field.value(&cell).to_bits()),
+    }
+}
+
+/// Returns the colour of faces of cells with key, see cell_key
+fn key_color(colors: &CellColors, key: u32) -> [f32; 4] {
+    match *colors {
+        CellColors::Material => material_color(key),
+        CellColors::Field { map, range, .. } => {
+            let (min, max) = range.unwrap_or((0.0, 0.0));
+            // A range of a single value maps everything to the middle of the map
+            if max > min {
+                map.sample((f32::from_bits(key) - min) / (max - min))
+            } else {
+                map.sample(0.5)
+            }
+        }
+    }
+}
+
+/// A layer of cells across one axis of the grid, see GridBuffer::gen_vertex
+struct Layer {
+    axis: usize, // Axis the layer lies across
+    u: usize,    // Axis the rows of the layer run along
+    v: usize,    // Axis the columns of the layer run along
+    width: u32,  // Number of cells along u
+    height: u32, // Number of cells along v
+}
+
+impl Layer {
+    fn new(grid_buffer: &GridBuffer, axis: usize) -> Layer {
+        let size = [
+            grid_buffer.xsize(),
+            grid_buffer.ysize(),
+            grid_buffer.zsize(),
+        ];
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        Layer {
+            axis,
+            u,
+            v,
+            width: size[u],
+            height: size[v],
+        }
+    }
+
+    /// Returns the keys of the cells at position along the axis, indexed by a + b * width for the
+    /// cell a along u and b along v
+    fn keys(&self, grid_buffer: &GridBuffer, view: &GridView, position: u32) -> Vec<Option<u32>> {
+        let mut keys = Vec::with_capacity((self.width * self.height) as usize);
+        for b in 0..self.height {
+            for a in 0..self.width {
+                let mut pos = [0; 3];
+                pos[self.axis] = position;
+                pos[self.u] = a;
+                pos[self.v] = b;
+                keys.push(cell_key(grid_buffer, view, pos));
+            }
+        }
+        keys
+    }
+
+    /// Covers the faces in mask with as few rectangles as it can, taking them greedily: each
+    /// rectangle starts at the first face left, grows along u as far as faces with the same key
+    /// go, then along v for as many whole rows as match. The faces lie in the plane at plane along
+    /// the axis, and face towards higher coordinates if positive
+    fn greedy_mesh(
+        &self,
+        mut mask: Vec<Option<u32>>,
+        plane: u32,
+        positive: bool,
+        colors: &CellColors,
+        vertex_list: &mut Vec<Vertex>,
+    ) {
+        let width = self.width as usize;
+        for b in 0..self.height as usize {
+            let mut a = 0;
+            while a < width {
+                let Some(key) = mask[a + b * width] else {
+                    a += 1;
+                    continue;
+                };
+                let mut w = 1;
+                while a + w < width && mask[a + w + b * width] == Some(key) {
+                    w += 1;
+                }
+                let mut h = 1;
+                while b + h < self.height as usize
+                    && mask[a + (b + h) * width..a + w + (b + h) * width]
+                        .iter()
+                        .all(|&other| other == Some(key))
+                {
+                    h += 1;
+                }
+                for row in b..b + h {
+                    mask[a + row * width..a + w + row * width].fill(None);
+                }
+                vertex_list.extend(self.quad(plane, positive, [a, b], [w, h], colors, key));
+                a += w;
+            }
+        }
+    }
+
+    /// Returns the two triangles of a rectangle of faces, from start spanning size cells along
+    /// u and v
+    fn quad(
+        &self,
+        plane: u32,
+        positive: bool,
+        start: [usize; 2],
+        size: [usize; 2],
+        colors: &CellColors,
+        key: u32,
+    ) -> [Vertex; 6] {
+        let color = key_color(colors, key);
+        let corner = |du: usize, dv: usize| {
+            let mut loc = [0.0; 3];
+            loc[self.axis] = plane as f32;
+            loc[self.u] = (start[0] + du * size[0]) as f32;
+            loc[self.v] = (start[1] + dv * size[1]) as f32;
+            Vertex { loc, color }
+        };
+        let corners = [corner(0, 0), corner(1, 0), corner(1, 1), corner(0, 1)];
+        // Wind the triangles counterclockwise as seen from the side the faces face
+        let order = if positive {
+            [0, 1, 2, 2, 3, 0]
+        } else {
+            [0, 3, 2, 2, 1, 0]
+        };
+        order.map(|i| corners[i])
+    }
+}
+
+impl GridBuffer {
+    /// Generates a list of vertexes to be rendered, drawing the cells view asks for. Only faces
+    /// between a drawn cell and one that isn't drawn are generated, and faces lying next to each
+    /// other in the same plane are merged into rectangles where they look alike, see
+    /// Layer::greedy_mesh
+    pub fn gen_vertex(&self, view: &GridView) -> Vec<Vertex> {
+        let view = view.fitted(self);
+        let mut vertex_list = Vec::new();
+        for axis in 0..3 {
+            let layer = Layer::new(self, axis);
+            let size = [self.xsize(), self.ysize(), self.zsize()][axis];
+            if size == 0 {
+                continue;
+            }
+            let empty = vec![None; (layer.width * layer.height) as usize];
+            // Walk the layers along the axis, keeping the keys of the layers on either side
+            let mut below = empty.clone();
+            let mut current = layer.keys(self, &view, 0);
+            for position in 0..size {
+                let above = if position + 1 < size {
+                    layer.keys(self, &view, position + 1)
+                } else {
+                    empty.clone()
+                };
+                for (neighbour, positive) in [(&below, false), (&above, true)] {
+                    // A face is hidden if the cell across it is drawn as well
+                    let mask = current
+                        .iter()
+                        .zip(neighbour)
+                        .map(|(&key, &across)| key.filter(|_| across.is_none()))
+                        .collect();
+                    let plane = position + positive as u32;
+                    layer.greedy_mesh(mask, plane, positive, &view.colors, &mut vertex_list);
+                }
+                below = std::mem::replace(&mut current, above);
+            }
+        }
+        vertex_list
+    }
+}
+
+/// The vertexes of a grid, kept until the grid or the way it is drawn changes so that large grids
+/// aren't meshed again every frame
+pub struct GridMesh {
+    vertices: Vec<Vertex>,
+    version: u64,             // Version of the grid the mesh was built from
+    view: Option<GridView>,   // View the mesh was asked for, None before it is first built
+    fitted: Option<GridView>, // View the mesh was built with, with its range filled in
+}
+
+impl Default for GridMesh {
+    fn default() -> GridMesh {
+        GridMesh::new()
+    }
+}
+
+impl GridMesh {
+    pub fn new() -> GridMesh {
+        GridMesh {
+            vertices: Vec::new(),
+            version: 0,
+            view: None,
+            fitted: None,
+        }
+    }
+
+    /// Meshes grid_buffer again if it has changed since the mesh was built, or if view differs
+    /// from the view it was built for. Returns true if the mesh was rebuilt
+    pub fn update(&mut self, grid_buffer: &GridBuffer, view: &GridView) -> bool {
+        if self.view.as_ref() == Some(view) && self.version == grid_buffer.version() {
+            return false;
+        }
+        let fitted = view.fitted(grid_buffer);
+        self.vertices = grid_buffer.gen_vertex(&fitted);
+        self.version = grid_buffer.version();
+        self.view = Some(*view);
+        self.fitted = Some(fitted);
+        true
+    }
+
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertices
+    }
+
+    /// Returns the view the mesh was built with, with the range of field colours filled in
+    pub fn view(&self) -> Option<&GridView> {
+        self.fitted.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::*;
+
+    /// Returns a grid of rolling hills of soil under air, with the moisture of every cell drawn
+    /// from a few values so that neighbouring cells sometimes match
+    fn hills(size: u32) -> GridBuffer {
+        let mut grid_buffer = GridBuffer::new(size, size, size);
+        let mut seed = 0x2545_f491_u32;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for x in 0..size {
+            for z in 0..size {
+                let height = random() % size;
+                for y in 0..size {
+                    let mut cell = GridCell::new();
+                    cell.typeCode = if y > height {
+                        GRIDCELL_TYPE_AIR
+                    } else {
+                        GRIDCELL_TYPE_SOIL
+                    };
+                    cell.moisture = random() % 3;
+                    grid_buffer.set(x, y, z, cell);
+                }
+            }
+        }
+        grid_buffer
+    }
+
+    /// Returns the number of faces between a cell view draws and one it doesn't, counting the
+    /// boundary of the grid as undrawn, for each direction a face can face: +x, -x, +y, -y, +z, -z
+    fn exposed_faces(grid_buffer: &GridBuffer, view: &GridView) -> [u32; 6] {
+        let size = [
+            grid_buffer.xsize(),
+            grid_buffer.ysize(),
+            grid_buffer.zsize(),
+        ];
+        let drawn = |pos: [i64; 3]| {
+            (0..3).all(|axis| pos[axis] >= 0 && pos[axis] < size[axis] as i64) && {
+                let [x, y, z] = pos.map(|c| c as u32);
+                view.shows(&grid_buffer.get(x, y, z), x, y, z)
+            }
+        };
+        let mut faces = [0; 6];
+        for x in 0..size[0] as i64 {
+            for y in 0..size[1] as i64 {
+                for z in 0..size[2] as i64 {
+                    if !drawn([x, y, z]) {
+                        continue;
+                    }
+                    for axis in 0..3 {
+                        for (k, step) in [1, -1].into_iter().enumerate() {
+                            let mut neighbour = [x, y, z];
+                            neighbour[axis] += step;
+                            if !drawn(neighbour) {
+                                faces[2 * axis + k] += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        faces
+    }
+
+    /// Returns the area of the triangles in vertices facing each direction, see exposed_faces
+    fn facing_area(vertices: &[Vertex]) -> [f32; 6] {
+        let mut areas = [0.0; 6];
+        for triangle in vertices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|k| triangle[k].loc);
+            let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+            let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+            let normal = [
+                u[1] * v[2] - u[2] * v[1],
+                u[2] * v[0] - u[0] * v[2],
+                u[0] * v[1] - u[1] * v[0],
+            ];
+            let axis = (0..3)
+                .max_by(|&i, &j| normal[i].abs().total_cmp(&normal[j].abs()))
+                .unwrap();
+            let k = (normal[axis] < 0.0) as usize;
+            areas[2 * axis + k] += normal[axis].abs() / 2.0;
+        }
+        areas
+    }
+
+    fn views() -> Vec<GridView> {
+        let mut field = GridView::new();
+        field.colors = CellColors::Field {
+            field: Field::Moisture,
+            map: ColorMap::Viridis,
+            range: None,
+        };
+        let mut sliced = field;
+        sliced.showAir = true;
+        sliced.slice = Some(Slice {
+            axis: Axis::Y,
+            position: 5,
+        });
+        let mut everything = GridView::new();
+        everything.showAir = true;
+        vec![GridView::new(), field, sliced, everything]
+    }
+
+    #[test]
+    fn mesh_covers_exactly_the_exposed_faces() {
+        let grid_buffer = hills(12);
+        for view in views() {
+            let vertices = grid_buffer.gen_vertex(&view);
+            let faces = exposed_faces(&grid_buffer, &view);
+            let areas = facing_area(&vertices);
+            for direction in 0..6 {
+                assert_eq!(areas[direction], faces[direction] as f32, "{}", view);
+            }
+            // Merging only pays off if it happens
+            let face_count: u32 = faces.iter().sum();
+            assert!(vertices.len() < 6 * face_count as usize, "{}", view);
+        }
+    }
+
+    #[test]
+    fn merged_faces_have_one_colour() {
+        let grid_buffer = hills(12);
+        for view in views() {
+            let vertices = grid_buffer.gen_vertex(&view);
+            for quad in vertices.chunks_exact(6) {
+                assert!(quad.iter().all(|vertex| vertex.color == quad[0].color));
+            }
+        }
+    }
+
+    #[test]
+    fn mesh_is_only_rebuilt_on_visible_changes() {
+        let mut grid_buffer = hills(4);
+        let view = GridView::new();
+        let mut mesh = GridMesh::new();
+        assert!(mesh.update(&grid_buffer, &view));
+        assert!(!mesh.update(&grid_buffer, &view));
+
+        let mut cell = grid_buffer.get(0, 0, 0);
+        grid_buffer.set(0, 0, 0, cell);
+        cell.organicMatter += 1;
+        grid_buffer.set(0, 0, 0, cell);
+        assert!(!mesh.update(&grid_buffer, &view));
+
+        cell.moisture += 1;
+        grid_buffer.set(0, 0, 0, cell);
+        assert!(mesh.update(&grid_buffer, &view));
+        let mut sliced = view;
+        sliced.slice = Some(Slice {
+            axis: Axis::X,
+            position: 0,
+        });
+        assert!(mesh.update(&grid_buffer, &sliced));
+    }
+}
@@ -0,0 +1,262 @@
+#![allow(dead_code)]
+use cgmath::{InnerSpace, Quaternion, Rad, Rotation, Rotation3, Vector3};
+use std::f32::consts::PI;
+
+use super::archetype::*;
+use super::node::*;
+use super::vertex::mVertex as Vertex;
+
+/// Number of segments a leaf blade is divided into along its midrib
+const BLADE_SEGMENTS: usize = 12;
+/// Width of the petiole relative to the widest point of the blade
+const PETIOLE_WIDTH: f32 = 0.1;
+/// Number of samples taken when integrating the blade outline
+const OUTLINE_SAMPLES: usize = 1024;
+
+/// Returns the width of the blade at fraction t of its length from the base, relative to its
+/// widest point. The outline is a sine arch, skewed so that it peaks at the archetype's widest
+/// point and sharpened by its outline exponent
+pub fn blade_outline(archetype: &Archetype, t: f32) -> f32 {
+    // Raising t to this power moves the peak of the sine from the middle to the widest point
+    let skew = 0.5f32.ln() / archetype.bladeWidestPoint.clamp(0.01, 0.99).ln();
+    (PI * t.powf(skew))
+        .sin()
+        .max(0.0)
+        .powf(archetype.bladeOutlineExponent)
+}
+
+/// Returns the area of the blade outline as a fraction of the rectangle around it
+fn outline_fill(archetype: &Archetype) -> f32 {
+    let sum: f32 = (0..OUTLINE_SAMPLES)
+        .map(|i| blade_outline(archetype, (i as f32 + 0.5) / OUTLINE_SAMPLES as f32))
+        .sum();
+    sum / OUTLINE_SAMPLES as f32
+}
+
+/// Returns the one sided area of the blade of a leaf of the given length and width, without the
+/// petiole. This is the area of the mesh made by blade_mesh
+pub fn leaf_area(archetype: &Archetype, length: f32, width: f32) -> f32 {
+    (1.0 - archetype.petioleFraction) * length * width * outline_fill(archetype)
+}
+
+/// Returns the total area of a list of triangles
+pub fn mesh_area(vertices: &[Vertex]) -> f32 {
+    vertices
+        .chunks_exact(3)
+        .map(|triangle| {
+            let a = Vector3::from(triangle[0].loc);
+            let b = Vector3::from(triangle[1].loc);
+            let c = Vector3::from(triangle[2].loc);
+            (b - a).cross(c - a).magnitude() / 2.0
+        })
+        .sum()
+}
+
+/// A cross section of a leaf: a point on the midrib, and half of the lamina's width across it
+struct Section {
+    center: Vector3<f32>,
+    half_width: Vector3<f32>,
+    color: [f32; 4],
+}
+
+/// Joins successive cross sections with two triangles each
+fn ribbon(sections: &[Section]) -> Vec<Vertex> {
+    let mut vertex_list = Vec::with_capacity(sections.len().saturating_sub(1) * 6);
+    for pair in sections.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+        let corners = [
+            (a.center - a.half_width, a.color),
+            (a.center + a.half_width, a.color),
+            (b.center - b.half_width, b.color),
+            (b.center - b.half_width, b.color),
+            (b.center + b.half_width, b.color),
+            (a.center + a.half_width, a.color),
+        ];
+        for (loc, color) in corners {
+            vertex_list.push(Vertex {
+                loc: loc.into(),
+                color,
+            });
+        }
+    }
+    vertex_list
+}
+
+/// The directions a leaf starts out in: along its midrib, out of its upper side, and across it
+struct LeafFrame {
+    axis: Vector3<f32>,
+    normal: Vector3<f32>,
+    side: Vector3<f32>,
+}
+
+impl LeafFrame {
+    /// Takes the directions from the node's world frame. The leaf lies across the node's z axis,
+    /// so that a leaf tilted away from its stem faces upwards. If the leaf angle is set, the leaf
+    /// is then turned towards or away from stem_axis until it makes that angle with it
+    fn new(
+        frame: &WorldFrame,
+        stem_axis: Option<Vector3<f32>>,
+        archetype: &Archetype,
+    ) -> LeafFrame {
+        let mut leaf = LeafFrame {
+            axis: frame.rotation.rotate_vector(Vector3::unit_y()),
+            normal: frame.rotation.rotate_vector(Vector3::unit_x()),
+            side: frame.rotation.rotate_vector(Vector3::unit_z()),
+        };
+        if let Some(stem) = stem_axis.filter(|_| archetype.leafAngle != 0.0) {
+            let hinge = stem.cross(leaf.axis);
+            if hinge.magnitude2() > f32::EPSILON {
+                let turn = Rad(archetype.leafAngle) - stem.angle(leaf.axis);
+                leaf.rotate(Quaternion::from_axis_angle(hinge.normalize(), turn));
+            }
+        }
+        leaf
+    }
+
+    fn rotate(&mut self, rotation: Quaternion<f32>) {
+        self.axis = rotation.rotate_vector(self.axis);
+        self.normal = rotation.rotate_vector(self.normal);
+        self.side = rotation.rotate_vector(self.side);
+    }
+}
+
+/// Generates the blade of a leaf, starting at base. The midrib bends downwards by the archetype's
+/// midrib curvature and the lamina twists about it by its lamina twist, both spread evenly over
+/// the blade. Widths follow blade_outline, scaled so that the area of the mesh equals leaf_area
+fn blade_mesh(
+    base: Vector3<f32>,
+    mut leaf: LeafFrame,
+    length: f32,
+    width: f32,
+    archetype: &Archetype,
+    color1: [f32; 4],
+    color2: [f32; 4],
+) -> Vec<Vertex> {
+    let step = length / BLADE_SEGMENTS as f32;
+    let outline: Vec<f32> = (0..=BLADE_SEGMENTS)
+        .map(|i| blade_outline(archetype, i as f32 / BLADE_SEGMENTS as f32))
+        .collect();
+    // Straight segments between the sections cut the curved outline short, make up for it
+    let tessellated: f32 =
+        outline.windows(2).map(|w| (w[0] + w[1]) / 2.0).sum::<f32>() / BLADE_SEGMENTS as f32;
+    let scale = match tessellated {
+        t if t > 0.0 => outline_fill(archetype) / t,
+        _ => 0.0,
+    };
+
+    let bend = Rad(archetype.midribCurvature / BLADE_SEGMENTS as f32);
+    let twist = Rad(archetype.laminaTwist / BLADE_SEGMENTS as f32);
+    let mut center = base;
+    let mut sections = Vec::with_capacity(BLADE_SEGMENTS + 1);
+    for (i, relative_width) in outline.into_iter().enumerate() {
+        let t = i as f32 / BLADE_SEGMENTS as f32;
+        sections.push(Section {
+            center,
+            half_width: leaf.side * (width * scale * relative_width / 2.0),
+            color: std::array::from_fn(|c| color1[c] + (color2[c] - color1[c]) * t),
+        });
+        // Bending about the side pulls the midrib down, away from the upper side. Half of the
+        // bend is applied before the step and half after, so the segment follows the arc
+        let droop = Quaternion::from_axis_angle(leaf.side, bend / 2.0);
+        leaf.rotate(droop);
+        center += leaf.axis * step;
+        leaf.rotate(droop);
+        leaf.rotate(Quaternion::from_axis_angle(leaf.axis, twist));
+    }
+    ribbon(&sections)
+}
+
+/// Generates a leaf growing from the world frame of its node, length long and width wide at
+/// its widest point: a straight petiole followed by the blade, see blade_mesh. stem_axis is the
+/// direction of the stem the leaf grows from, if any, which the archetype's leaf angle is
+/// measured from
+pub fn leaf_mesh(
+    frame: &WorldFrame,
+    stem_axis: Option<Vector3<f32>>,
+    length: f32,
+    width: f32,
+    archetype: &Archetype,
+    color1: [f32; 4],
+    color2: [f32; 4],
+) -> Vec<Vertex> {
+    let leaf = LeafFrame::new(frame, stem_axis, archetype);
+    let petiole_length = archetype.petioleFraction * length;
+    let blade_base = frame.base + leaf.axis * petiole_length;
+
+    let mut vertex_list = Vec::new();
+    if petiole_length > 0.0 {
+        let half_width = leaf.side * (width * PETIOLE_WIDTH / 2.0);
+        vertex_list = ribbon(&[
+            Section {
+                center: frame.base,
+                half_width,
+                color: color1,
+            },
+            Section {
+                center: blade_base,
+                half_width,
+                color: color1,
+            },
+        ]);
+    }
+    vertex_list.append(&mut blade_mesh(
+        blade_base,
+        leaf,
+        length - petiole_length,
+        width,
+        archetype,
+        color1,
+        color2,
+    ));
+    vertex_list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the area of the mesh leaf_mesh makes for archetype, and the area of the blade and
+    /// petiole it should have
+    fn areas(archetype: &Archetype, stem_axis: Option<Vector3<f32>>) -> (f32, f32) {
+        let (length, width) = (0.3, 0.1);
+        let mesh = leaf_mesh(
+            &WorldFrame::new(),
+            stem_axis,
+            length,
+            width,
+            archetype,
+            [0.0; 4],
+            [1.0; 4],
+        );
+        let petiole = archetype.petioleFraction * length * width * PETIOLE_WIDTH;
+        (
+            mesh_area(&mesh),
+            leaf_area(archetype, length, width) + petiole,
+        )
+    }
+
+    #[test]
+    fn flat_leaf_mesh_has_leaf_area() {
+        let archetype_table = ArchetypeTable::new();
+        let mut archetype = archetype_table.get(LEAF_ARCHETYPE_INDEX).unwrap().clone();
+        archetype.midribCurvature = 0.0;
+        archetype.laminaTwist = 0.0;
+        for (widest, exponent) in [(0.5, 0.0), (0.4, 1.0), (0.2, 3.0)] {
+            archetype.bladeWidestPoint = widest;
+            archetype.bladeOutlineExponent = exponent;
+            let (mesh, expected) = areas(&archetype, None);
+            assert!((mesh - expected).abs() < 1.0e-3 * expected);
+        }
+    }
+
+    #[test]
+    fn bending_keeps_leaf_area() {
+        let archetype_table = ArchetypeTable::new();
+        let mut archetype = archetype_table.get(LEAF_ARCHETYPE_INDEX).unwrap().clone();
+        archetype.midribCurvature = 1.0;
+        archetype.laminaTwist = 0.5;
+        let (mesh, expected) = areas(&archetype, Some(Vector3::unit_x()));
+        // Twisting warps the quads between sections a little
+        assert!((mesh - expected).abs() < 1.0e-2 * expected);
+    }
+}
@@ -0,0 +1,153 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+use super::archetype::*;
+use super::error::*;
+use super::grid::*;
+use super::node::*;
+use super::plant::*;
+
+/// Carbon debt beyond which a plant starves to death
+pub const STARVATION_CARBON: f32 = -0.001;
+/// Mean soil moisture below which a plant suffers water stress
+pub const WILTING_MOISTURE: f32 = 20.0;
+/// Number of ticks a well watered plant survives once the soil dries out
+pub const DROUGHT_TOLERANCE: u32 = 2000;
+/// Volume below which a dead organ counts as fully decomposed
+pub const DECOMPOSED_VOLUME: f32 = 1.0e-9;
+
+// Cubic millimetres per cubic metre, used to convert node volumes into soil organic matter
+const MM3_PER_M3: f32 = 1.0e9;
+
+/// Kills plants that have starved or dried out, along with all of their organs, and kills organs
+/// that have reached their maximum age. Dead organs stay in place as standing litter, which
+/// decomposes into the soil cell beneath their plant. Litter is freed from the tips down once it
+/// has fully decomposed, and dead plants are freed once none of their nodes are left. Returns an
+/// error if freeing litter fails
+pub fn update_lifecycle(
+    node_buffer: &mut NodeBuffer,
+    plant_buffer: &mut PlantBuffer,
+    grid_buffer: &mut GridBuffer,
+    archetype_table: &ArchetypeTable,
+) -> Result<()> {
+    for pi in 0..plant_buffer.size() {
+        let mut plant = plant_buffer.get(pi);
+        if plant.status != STATUS_ALIVE {
+            continue;
+        }
+
+        let moisture = grid_buffer
+            .conditions_beneath(plant.location)
+            .map_or(0.0, |(moisture, _)| moisture);
+        let stress_delta = 1.0 / DROUGHT_TOLERANCE as f32;
+        if moisture < WILTING_MOISTURE {
+            plant.waterStress = (plant.waterStress + stress_delta).min(1.0);
+        } else {
+            plant.waterStress = (plant.waterStress - stress_delta).max(0.0);
+        }
+
+        if plant.carbon < STARVATION_CARBON || plant.waterStress >= 1.0 {
+            plant.status = STATUS_DEAD;
+        }
+        plant_buffer.set(pi, plant);
+    }
+
+    // Number of nodes left on each plant
+    let mut node_counts = vec![0u32; plant_buffer.size() as usize];
+
+    for ni in 0..node_buffer.size() {
+        let mut node = node_buffer.get(ni);
+        if node.status == STATUS_GARBAGE {
+            continue;
+        }
+        // Nodes whose plant has already been freed die along with it, their litter is lost
+        let plant = plant_buffer.get_handle(node.plant_handle());
+        let plant_dead = match plant {
+            Some(p) => p.status == STATUS_DEAD,
+            None => true,
+        };
+        let archetype = archetype_table.get(node.archetypeId);
+
+        if node.status == STATUS_ALIVE {
+            let maxAge = archetype.map_or(0, |a| a.maxAge);
+            if plant_dead || (maxAge != 0 && node.age >= maxAge) {
+                node.status = STATUS_DEAD;
+            }
+        }
+
+        if node.status == STATUS_DEAD {
+            let decayed = node.volume * archetype.map_or(1.0, |a| a.decayRate);
+            // Only whole cubic millimetres reach the soil. Counting them off the volume left,
+            // rather than off what decayed this tick, carries the fractions over to later ticks
+            let before = (node.volume * MM3_PER_M3) as u32;
+            node.volume -= decayed;
+            let deposited = before.saturating_sub((node.volume * MM3_PER_M3) as u32);
+            if let Some((x, y, z)) = plant
+                .filter(|_| deposited > 0)
+                .and_then(|p| grid_buffer.cell_beneath(p.location))
+            {
+                let mut cell = grid_buffer.get(x, y, z);
+                cell.organicMatter = cell.organicMatter.saturating_add(deposited);
+                grid_buffer.set(x, y, z, cell);
+            }
+        }
+
+        if node.status != STATUS_ALIVE
+            && node.volume <= DECOMPOSED_VOLUME
+            && node.firstChildIndex == INVALID_INDEX
+        {
+            node_buffer.free_subtree(ni)?;
+            continue;
+        }
+
+        node_buffer.set(ni, node);
+        if plant.is_some() {
+            node_counts[node.plantId as usize] += 1;
+        }
+    }
+
+    for pi in 0..plant_buffer.size() {
+        if plant_buffer.get(pi).status == STATUS_DEAD && node_counts[pi as usize] == 0 {
+            plant_buffer.free(pi);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_decay_reaches_the_soil() {
+        let archetype_table = ArchetypeTable::new();
+        let mut grid_buffer = GridBuffer::new(1, 2, 1);
+        let mut plant_buffer = PlantBuffer::new(1);
+        let mut plant = Plant::new();
+        plant.status = STATUS_DEAD;
+        plant.location = [0.5, 1.0, 0.5];
+        let plant_handle = plant_buffer.alloc_insert(plant);
+        let mut node_buffer = NodeBuffer::new(1);
+        let mut node = Node::new();
+        node.status = STATUS_DEAD;
+        node.archetypeId = STEM_ARCHETYPE_INDEX;
+        node.set_plant(plant_handle);
+        // Decays by a tenth of a cubic millimetre every tick
+        node.volume = 1.0e-7;
+        let index = node_buffer.alloc();
+        node_buffer.set(index, node);
+
+        for _ in 0..1000 {
+            update_lifecycle(
+                &mut node_buffer,
+                &mut plant_buffer,
+                &mut grid_buffer,
+                &archetype_table,
+            )
+            .unwrap();
+        }
+        let left = (node_buffer.get(index).volume * MM3_PER_M3) as u32;
+        let organicMatter = grid_buffer.get(0, 0, 0).organicMatter;
+        assert!(organicMatter > 0);
+        assert_eq!(organicMatter + left, (1.0e-7 * MM3_PER_M3) as u32);
+    }
+}
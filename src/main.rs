@@ -1,4 +1,4 @@
-use cgmath::Point3;
+use cgmath::{EuclideanSpace, Point3};
 use vulkano::pipeline::graphics::GraphicsPipelineCreateInfo;
 use vulkano::pipeline::graphics::color_blend::{ColorBlendState, ColorBlendAttachmentState};
 use vulkano::pipeline::graphics::depth_stencil::{DepthStencilState, DepthState};
@@ -30,27 +30,81 @@ use vulkano::sync::GpuFuture;
 use vulkano::{format::*, Validated, VulkanLibrary};
 use vulkano::{sync, VulkanError};
 
-use winit::event::{Event, WindowEvent, VirtualKeyCode};
+use winit::event::{ElementState, Event, VirtualKeyCode, WindowEvent};
 use winit::window::{Window, WindowBuilder};
 
 mod archetype;
+mod bench;
 mod camera;
+mod color;
+mod cylinder;
+mod edit;
+mod error;
+mod export;
+#[cfg(test)]
+mod fixtures;
+mod germination;
 mod grid;
+mod gridmesh;
+mod leaf;
+mod lifecycle;
+mod metamer;
 mod node;
 mod plant;
+mod reproduction;
 mod shader;
+mod slot;
+mod traversal;
 mod util;
+mod validate;
 mod vertex;
 
 use archetype::*;
 use camera::*;
+use color::*;
+use cylinder::*;
+use error::*;
+use germination::*;
 use grid::*;
+use gridmesh::*;
+use lifecycle::*;
+use metamer::*;
 use node::*;
 use plant::*;
+use reproduction::*;
 
 use crate::vertex::mVertex;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    // `--bench` runs the benchmarks instead of opening the viewer
+    if args.iter().any(|arg| arg == "--bench") {
+        bench::run_benchmarks();
+        return;
+    }
+    // `--validate [ticks]` runs the simulation without the viewer, validating it after every tick
+    if let Some(position) = args.iter().position(|arg| arg == "--validate") {
+        let ticks = args
+            .get(position + 1)
+            .and_then(|ticks| ticks.parse().ok())
+            .unwrap_or(10000);
+        std::process::exit(if run_validation(ticks) { 0 } else { 1 });
+    }
+    // `--export <path> [ticks]` runs the simulation without the viewer, then writes the plants
+    // to path as a Wavefront OBJ mesh
+    if let Some(position) = args.iter().position(|arg| arg == "--export") {
+        let path = args.get(position + 1).map_or("plants.obj", |path| path.as_str());
+        let ticks = args
+            .get(position + 2)
+            .and_then(|ticks| ticks.parse().ok())
+            .unwrap_or(10000);
+        if let Err(e) = run_export(path, ticks) {
+            println!("Export failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let library = VulkanLibrary::new().unwrap();
     let event_loop = EventLoop::new();
     let required_extensions = Surface::required_extensions(&event_loop);
@@ -267,64 +321,7 @@ fn main() {
 
     //Compute stuff
 
-    // The 3d size of the simulation in meters
-    let sim_x_size: u32 = 10;
-    let sim_y_size: u32 = 10;
-    let sim_z_size: u32 = 10;
-
-    let mut plant_buffer = PlantBuffer::new(50);
-    //
-    // The maximum node capacity of the node buffer
-    let mut node_buffer = NodeBuffer::new(50);
-    for i in 0..5 {
-        let pindex = plant_buffer.alloc();
-        let mut plant = Plant::new();
-
-        plant.location = [0.0, i as f32, 0.0];
-        plant.status = STATUS_ALIVE;
-        plant_buffer.set(pindex, plant);
-
-        let nindex = node_buffer.alloc();
-
-        let mut node = Node::new();
-
-        node.status = STATUS_ALIVE;
-        node.archetypeId = GROWING_BUD_ARCHETYPE_INDEX;
-        node.visible = 1;
-        node.plantId = pindex;
-        node.length = 0.05;
-        node.radius = 0.01;
-        node.volume = 0.1;
-
-        node_buffer.set(nindex, node);
-    }
-    let mut grid_buffer = GridBuffer::new(sim_x_size, sim_y_size, sim_z_size);
-
-    for x in 0..sim_x_size {
-        for z in 0..sim_y_size {
-            let height = ((sim_y_size as f32) * rand::random::<f32>()) as u32;
-            for y in 0..sim_z_size {
-                grid_buffer.set(
-                    x,
-                    y,
-                    z,
-                    GridCell {
-                        //Initialize the array to be filled with dirt halfway
-                        typeCode: if y > height {
-                            grid::GRIDCELL_TYPE_AIR
-                        } else {
-                            grid::GRIDCELL_TYPE_SOIL
-                        },
-                        temperature: 0,
-                        moisture: 0,
-                        sunlight: 0,
-                        gravity: 0,
-                        plantDensity: 0,
-                    },
-                );
-            }
-        }
-    }
+    let (archetype_table, mut plant_buffer, mut node_buffer, mut grid_buffer) = create_world();
 
     let mut recreate_swapchain = false;
 
@@ -332,6 +329,18 @@ fn main() {
 
     let mut startTime = std::time::Instant::now();
     let mut frameCount = 0;
+    let mut tick: u32 = 0;
+
+    // Attribute nodes are coloured by, or None for archetype colours, and the map it goes through
+    let mut color_attribute: Option<Attribute> = None;
+    let mut color_map = ColorMap::Viridis;
+    // Field grid cells are coloured by, or None for material colours, and the cells drawn
+    let mut grid_field: Option<Field> = None;
+    let mut grid_view = GridView::new();
+    // The grid is only meshed and uploaded again when it or the way it is drawn changes
+    let mut grid_mesh = GridMesh::new();
+    let mut grid_vertex_buffer = None;
+    let mut title = String::new();
 
     event_loop.run(move |event, _, control_flow| {
         match event {
@@ -364,6 +373,67 @@ fn main() {
                         VirtualKeyCode::Left => camera.dir_rotate(CameraRotationDir::Left),
                         VirtualKeyCode::Down => camera.dir_rotate(CameraRotationDir::Downward),
                         VirtualKeyCode::Right => camera.dir_rotate(CameraRotationDir::Right),
+
+                        // Cycle from archetype colours through every attribute and back
+                        VirtualKeyCode::C if input.state == ElementState::Pressed => {
+                            color_attribute = match color_attribute {
+                                None => Some(Attribute::ALL[0]),
+                                Some(current) => Attribute::ALL
+                                    .into_iter()
+                                    .skip_while(|&attribute| attribute != current)
+                                    .nth(1),
+                            }
+                        }
+                        VirtualKeyCode::M if input.state == ElementState::Pressed => {
+                            color_map = color_map.next()
+                        }
+
+                        // Cycle from material colours through every grid field and back
+                        VirtualKeyCode::G if input.state == ElementState::Pressed => {
+                            grid_field = match grid_field {
+                                None => Some(Field::ALL[0]),
+                                Some(current) => Field::ALL
+                                    .into_iter()
+                                    .skip_while(|&field| field != current)
+                                    .nth(1),
+                            }
+                        }
+                        // Cycle the slicing plane from none through every axis and back
+                        VirtualKeyCode::V if input.state == ElementState::Pressed => {
+                            grid_view.slice = match grid_view.slice.map(|slice| slice.axis) {
+                                None => Some(Axis::X),
+                                Some(Axis::X) => Some(Axis::Y),
+                                Some(Axis::Y) => Some(Axis::Z),
+                                Some(Axis::Z) => None,
+                            }
+                            .map(|axis| Slice { axis, position: 0 })
+                        }
+                        VirtualKeyCode::LBracket if input.state == ElementState::Pressed => {
+                            grid_view.slice =
+                                grid_view.slice.map(|slice| slice.moved(-1, &grid_buffer))
+                        }
+                        VirtualKeyCode::RBracket if input.state == ElementState::Pressed => {
+                            grid_view.slice =
+                                grid_view.slice.map(|slice| slice.moved(1, &grid_buffer))
+                        }
+                        VirtualKeyCode::O if input.state == ElementState::Pressed => {
+                            grid_view.showAir = !grid_view.showAir
+                        }
+                        VirtualKeyCode::P if input.state == ElementState::Pressed => {
+                            grid_view.showSoil = !grid_view.showSoil
+                        }
+                        // Print how full the buffers are and what the plants are made of
+                        VirtualKeyCode::I if input.state == ElementState::Pressed => {
+                            println!(
+                                "Plants: {} (peak {}), Nodes: {} (peak {})",
+                                plant_buffer.current_size(),
+                                plant_buffer.high_water_mark(),
+                                node_buffer.current_size(),
+                                node_buffer.high_water_mark()
+                            );
+                            let metamers = node_buffer.metamers();
+                            println!("{}", MetamerStats::new(&metamers, &node_buffer));
+                        }
                         _ => (),
                     }
                 }
@@ -393,10 +463,82 @@ fn main() {
                     return;
                 }
 
-                node_buffer.update_all();
+                if let Err(e) = step(
+                    &mut node_buffer,
+                    &mut plant_buffer,
+                    &mut grid_buffer,
+                    &archetype_table,
+                    tick,
+                ) {
+                    println!("Simulation step failed: {}", e);
+                }
+                tick = tick.wrapping_add(1);
+                let colors = match color_attribute {
+                    None => NodeColors::Archetype(Shading::new()),
+                    Some(attribute) => {
+                        // Stretch the map over the values currently on screen
+                        let (min, max) = node_buffer
+                            .attribute_range(attribute, &plant_buffer)
+                            .unwrap_or((0.0, 0.0));
+                        NodeColors::Attribute {
+                            attribute,
+                            map: color_map,
+                            min,
+                            max,
+                        }
+                    }
+                };
+                grid_view.colors = match grid_field {
+                    None => CellColors::Material,
+                    Some(field) => CellColors::Field {
+                        field,
+                        map: color_map,
+                        range: None,
+                    },
+                };
+                if grid_mesh.update(&grid_buffer, &grid_view) {
+                    grid_vertex_buffer = (!grid_mesh.vertices().is_empty()).then(|| {
+                        Buffer::from_iter(
+                            memory_allocator.clone(),
+                            BufferCreateInfo {
+                                usage: BufferUsage::VERTEX_BUFFER,
+                                ..Default::default()
+                            },
+                            AllocationCreateInfo {
+                                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                                ..Default::default()
+                            },
+                            grid_mesh.vertices().iter().cloned(),
+                        )
+                        .unwrap()
+                    });
+                }
+                // Show the mappings in the title bar, only touching it when they change
+                let new_title = format!(
+                    "compugenesis: nodes {}, grid {}",
+                    colors,
+                    grid_mesh.view().unwrap_or(&grid_view)
+                );
+                if new_title != title {
+                    window.set_title(&new_title);
+                    title = new_title;
+                }
                 let vertex_buffer = {
-                    let mut vecs = node_buffer.gen_vertex(&plant_buffer);
-                    vecs.append(&mut grid_buffer.gen_vertex());
+                    // Stems far from the camera get fewer sides
+                    let style = CylinderStyle {
+                        ends: Ends::Joined,
+                        resolution: Resolution::ByDistance {
+                            eye: camera.loc().to_vec(),
+                            angle: 0.05,
+                        },
+                    };
+                    let vecs = node_buffer.gen_vertex(
+                        &plant_buffer,
+                        &archetype_table,
+                        &style,
+                        &colors,
+                    );
                     Buffer::from_iter(
                         memory_allocator.clone(),
                         BufferCreateInfo {
@@ -510,12 +652,18 @@ fn main() {
                     )
                     .unwrap()
                     .draw(vertex_buffer.len() as u32, 1, 0, 0)
-                    .unwrap()
-                    // We leave the render pass by calling `draw_end`. Note that if we had multiple
-                    // subpasses we could have called `next_inline` (or `next_secondary`) to jump to the
-                    // next subpass.
-                    .end_render_pass(Default::default())
                     .unwrap();
+                if let Some(grid_vertex_buffer) = &grid_vertex_buffer {
+                    builder
+                        .bind_vertex_buffers(0, grid_vertex_buffer.clone())
+                        .unwrap()
+                        .draw(grid_vertex_buffer.len() as u32, 1, 0, 0)
+                        .unwrap();
+                }
+                // We leave the render pass by calling `draw_end`. Note that if we had multiple
+                // subpasses we could have called `next_inline` (or `next_secondary`) to jump to the
+                // next subpass.
+                builder.end_render_pass(Default::default()).unwrap();
 
                 let command_buffer = builder.build().unwrap();
 
@@ -556,6 +704,158 @@ fn main() {
     });
 }
 
+/// Creates the archetypes, the demo plants and the grid they grow in
+fn create_world() -> (ArchetypeTable, PlantBuffer, NodeBuffer, GridBuffer) {
+    // The 3d size of the simulation in meters
+    let sim_x_size: u32 = 10;
+    let sim_y_size: u32 = 10;
+    let sim_z_size: u32 = 10;
+
+    // The parameters for each archetype of node
+    let archetype_table = ArchetypeTable::new();
+
+    // The initial plant capacity of the plant buffer, it grows as needed
+    let mut plant_buffer = PlantBuffer::new(50);
+    //
+    // The initial node capacity of the node buffer, it grows as needed
+    let mut node_buffer = NodeBuffer::new(50);
+    // Start the demo plants from seeds that germinate immediately
+    let seed_carbon = archetype_table.get(FRUIT_ARCHETYPE_INDEX).unwrap().seedCost;
+    for i in 0..5 {
+        let pindex = sow(&mut plant_buffer, [0.0, i as f32, 0.0], seed_carbon).unwrap();
+        germinate(&mut node_buffer, &mut plant_buffer, pindex).unwrap();
+    }
+    let mut grid_buffer = GridBuffer::new(sim_x_size, sim_y_size, sim_z_size);
+
+    for x in 0..sim_x_size {
+        for z in 0..sim_y_size {
+            let height = ((sim_y_size as f32) * rand::random::<f32>()) as u32;
+            for y in 0..sim_z_size {
+                grid_buffer.set(
+                    x,
+                    y,
+                    z,
+                    GridCell {
+                        //Initialize the array to be filled with dirt halfway
+                        typeCode: if y > height {
+                            grid::GRIDCELL_TYPE_AIR
+                        } else {
+                            grid::GRIDCELL_TYPE_SOIL
+                        },
+                        temperature: 20,
+                        moisture: 50,
+                        sunlight: 0,
+                        gravity: 0,
+                        plantDensity: 0,
+                        organicMatter: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    (archetype_table, plant_buffer, node_buffer, grid_buffer)
+}
+
+/// Ticks between compactions of the node buffer, see step
+const COMPACT_INTERVAL: u32 = 100;
+/// How stem segments grow, according to where their metamer sits on the plant
+const STEM_GROWTH: TaperedElongation = TaperedElongation::new();
+
+/// Advances the simulation by one tick, counted from zero. Every stage is run even if an earlier
+/// one fails, and the first error encountered is returned. Every COMPACT_INTERVAL ticks the node
+/// buffer is compacted, keeping the nodes of each plant next to each other as plants grow and die
+fn step(
+    node_buffer: &mut NodeBuffer,
+    plant_buffer: &mut PlantBuffer,
+    grid_buffer: &mut GridBuffer,
+    archetype_table: &ArchetypeTable,
+    tick: u32,
+) -> Result<()> {
+    plant_buffer.update_all();
+    let grown = node_buffer.update_all(archetype_table, plant_buffer, &STEM_GROWTH);
+    let dispersed = disperse_seeds(node_buffer, plant_buffer, grid_buffer, archetype_table);
+    let germinated = germinate_seeds(node_buffer, plant_buffer, grid_buffer, archetype_table);
+    let died = update_lifecycle(node_buffer, plant_buffer, grid_buffer, archetype_table);
+    if tick % COMPACT_INTERVAL == COMPACT_INTERVAL - 1 {
+        node_buffer.compact();
+    }
+    node_buffer.update_frames(plant_buffer);
+    // Catch corruption at the tick it happens, validating is too slow for release builds
+    if cfg!(debug_assertions) && !report_violations(node_buffer, plant_buffer) {
+        panic!("Simulation buffers are corrupt");
+    }
+    grown.and(dispersed).and(germinated).and(died)
+}
+
+/// Validates both buffers and prints every violation found. Returns true if there were none
+fn report_violations(node_buffer: &NodeBuffer, plant_buffer: &PlantBuffer) -> bool {
+    let node_violations = node_buffer.validate();
+    let plant_violations = plant_buffer.validate();
+    for violation in node_violations.iter() {
+        println!("NodeBuffer: {}", violation);
+    }
+    for violation in plant_violations.iter() {
+        println!("PlantBuffer: {}", violation);
+    }
+    node_violations.is_empty() && plant_violations.is_empty()
+}
+
+/// Runs the simulation for the given number of ticks without opening the viewer, validating the
+/// buffers after every tick. Returns false as soon as a violation is found
+fn run_validation(ticks: u32) -> bool {
+    let (archetype_table, mut plant_buffer, mut node_buffer, mut grid_buffer) = create_world();
+    for tick in 0..ticks {
+        if let Err(e) = step(
+            &mut node_buffer,
+            &mut plant_buffer,
+            &mut grid_buffer,
+            &archetype_table,
+            tick,
+        ) {
+            println!("Simulation step failed: {}", e);
+        }
+        if !report_violations(&node_buffer, &plant_buffer) {
+            println!("Buffers corrupted at tick {}", tick);
+            return false;
+        }
+    }
+    println!(
+        "{} ticks without violations, {} plants and {} nodes left",
+        ticks,
+        plant_buffer.current_size(),
+        node_buffer.current_size()
+    );
+    true
+}
+
+/// Runs the simulation for ticks ticks, then writes the plants to path as a Wavefront OBJ mesh.
+/// The mesh doesn't depend on where it is viewed from, so stems get sides by their radius
+fn run_export(path: &str, ticks: u32) -> std::io::Result<()> {
+    let (archetype_table, mut plant_buffer, mut node_buffer, mut grid_buffer) = create_world();
+    for tick in 0..ticks {
+        if let Err(e) = step(
+            &mut node_buffer,
+            &mut plant_buffer,
+            &mut grid_buffer,
+            &archetype_table,
+            tick,
+        ) {
+            println!("Simulation step failed: {}", e);
+        }
+    }
+    let style = CylinderStyle {
+        ends: Ends::Joined,
+        resolution: Resolution::ByRadius { spacing: 0.002 },
+    };
+    let colors = NodeColors::Archetype(Shading::new());
+    let vertices = node_buffer.gen_vertex(&plant_buffer, &archetype_table, &style, &colors);
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    export::write_obj(&vertices, &mut file)?;
+    println!("Wrote {} triangles to {}", vertices.len() / 3, path);
+    Ok(())
+}
+
 /// This function is called once during initialization, then again whenever the window is resized.
 fn window_size_dependent_setup(
     memory_allocator: Arc<StandardMemoryAllocator>,
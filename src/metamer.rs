@@ -0,0 +1,429 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+use std::fmt;
+
+use super::archetype::*;
+use super::node::*;
+use super::plant::*;
+
+/// A metamer, or phytomer: a stem segment (the internode), the node at its tip, and the organs
+/// growing out of that node. Metamers are stacked along axes: the first child of an internode
+/// continues its axis, and every other child is a lateral organ. A lateral stem segment is the
+/// first internode of a branch
+#[derive(Clone, Debug)]
+pub struct Metamer {
+    pub internode: u32,
+    pub plant: PlantHandle,
+    pub leaves: Vec<u32>,
+    pub axillaryBuds: Vec<u32>, // Growing buds in the axils, which may break into branches
+    pub flowers: Vec<u32>,      // Flowers and fruits
+    pub branches: Vec<u32>,     // First internodes of the branches growing from the node
+    pub apex: u32, // Organ ending the axis above the top metamer, INVALID_INDEX on other metamers
+    pub rank: u32, // Branching order of the axis, 0 for the main stem
+    pub phytomer: u32, // Position along the axis, 1 for the lowest metamer
+}
+
+impl Metamer {
+    fn new(internode: u32, plant: PlantHandle, rank: u32, phytomer: u32) -> Metamer {
+        Metamer {
+            internode,
+            plant,
+            leaves: Vec::new(),
+            axillaryBuds: Vec::new(),
+            flowers: Vec::new(),
+            branches: Vec::new(),
+            apex: INVALID_INDEX,
+            rank,
+            phytomer,
+        }
+    }
+
+    /// Returns true if this is the top metamer of its axis
+    pub fn is_top(&self) -> bool {
+        self.apex != INVALID_INDEX
+    }
+
+    /// Returns the volume of the internode together with the organs of the metamer, without
+    /// the branches
+    pub fn volume(&self, node_buffer: &NodeBuffer) -> f32 {
+        let nodes = node_buffer.columns();
+        std::iter::once(self.internode)
+            .chain(self.leaves.iter().copied())
+            .chain(self.axillaryBuds.iter().copied())
+            .chain(self.flowers.iter().copied())
+            .map(|index| nodes.volume[index as usize])
+            .sum()
+    }
+}
+
+/// Grows the internodes of metamers according to where they sit on the plant, see
+/// NodeBuffer::update_all
+pub trait MetamerRule {
+    /// Returns the length and radius of an internode one tick after it had length and radius,
+    /// given the rank and phytomer number of its metamer
+    fn grow(&self, length: f32, radius: f32, rank: u32, phytomer: u32) -> (f32, f32);
+}
+
+/// Elongates internodes logistically towards a final length that shrinks by rankFactor with
+/// every branching, and by phytomerFactor with every metamer up the axis, so that branches stay
+/// shorter than the axes they grow from. Internodes thicken logistically towards maxRadius
+#[derive(Clone, Debug)]
+pub struct TaperedElongation {
+    pub maxLength: f32,
+    pub lengthScale: f32,
+    pub maxRadius: f32,
+    pub radiusScale: f32,
+    pub rankFactor: f32,
+    pub phytomerFactor: f32,
+}
+
+impl Default for TaperedElongation {
+    fn default() -> TaperedElongation {
+        TaperedElongation::new()
+    }
+}
+
+impl TaperedElongation {
+    /// Grows the main stem as stem segments have always grown, and every branch to 0.8 times the
+    /// length of the axis it grows from
+    pub const fn new() -> TaperedElongation {
+        TaperedElongation {
+            maxLength: 0.1,
+            lengthScale: 1.0,
+            maxRadius: 0.02,
+            radiusScale: 1.0,
+            rankFactor: 0.8,
+            phytomerFactor: 1.0,
+        }
+    }
+
+    /// Returns the length internodes of metamers at rank and phytomer grow towards
+    pub fn final_length(&self, rank: u32, phytomer: u32) -> f32 {
+        let length = self.maxLength * self.rankFactor.powi(rank as i32);
+        // Long axes make powi slow, so it is skipped when there is no taper along them
+        if self.phytomerFactor == 1.0 {
+            length
+        } else {
+            length * self.phytomerFactor.powi(phytomer.saturating_sub(1) as i32)
+        }
+    }
+}
+
+impl MetamerRule for TaperedElongation {
+    fn grow(&self, length: f32, radius: f32, rank: u32, phytomer: u32) -> (f32, f32) {
+        let final_length = self.final_length(rank, phytomer);
+        (
+            length + logisticDelta(length, final_length, self.lengthScale),
+            radius + logisticDelta(radius, self.maxRadius, self.radiusScale),
+        )
+    }
+}
+
+/// Totals over a set of metamers, see MetamerStats::new
+#[derive(Clone, Debug)]
+pub struct MetamerStats {
+    pub metamers: u32,
+    pub leaves: u32,
+    pub axillaryBuds: u32,
+    pub flowers: u32,
+    pub maxPhytomer: u32,
+    pub meanInternodeLength: f32,
+    pub perRank: Vec<u32>, // Number of metamers of every rank, indexed by rank
+}
+
+impl MetamerStats {
+    pub fn new(metamers: &[Metamer], node_buffer: &NodeBuffer) -> MetamerStats {
+        let mut stats = MetamerStats {
+            metamers: metamers.len() as u32,
+            leaves: 0,
+            axillaryBuds: 0,
+            flowers: 0,
+            maxPhytomer: 0,
+            meanInternodeLength: 0.0,
+            perRank: Vec::new(),
+        };
+        let mut total_length = 0.0;
+        for metamer in metamers {
+            stats.leaves += metamer.leaves.len() as u32;
+            stats.axillaryBuds += metamer.axillaryBuds.len() as u32;
+            stats.flowers += metamer.flowers.len() as u32;
+            stats.maxPhytomer = stats.maxPhytomer.max(metamer.phytomer);
+            if metamer.rank as usize >= stats.perRank.len() {
+                stats.perRank.resize(metamer.rank as usize + 1, 0);
+            }
+            stats.perRank[metamer.rank as usize] += 1;
+            total_length += node_buffer.columns().length[metamer.internode as usize];
+        }
+        if !metamers.is_empty() {
+            stats.meanInternodeLength = total_length / metamers.len() as f32;
+        }
+        stats
+    }
+}
+
+impl fmt::Display for MetamerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Metamers: {} (per rank {:?}, up to phytomer {}, mean internode {:.3}), Leaves: {}, \
+             Axillary buds: {}, Flowers: {}",
+            self.metamers,
+            self.perRank,
+            self.maxPhytomer,
+            self.meanInternodeLength,
+            self.leaves,
+            self.axillaryBuds,
+            self.flowers
+        )
+    }
+}
+
+/// Numbers the nodes of a buffer of size slots along their axes, see
+/// NodeBuffer::metamer_positions. links returns the archetype, parent and first child of the node
+/// at an index less than size, or None for garbage, so that the numbering works on nodes stored in
+/// any layout
+pub fn number_metamers(
+    size: u32,
+    links: impl Fn(u32) -> Option<(u32, u32, u32)>,
+) -> Vec<(u32, u32)> {
+    const UNNUMBERED: (u32, u32) = (u32::MAX, u32::MAX);
+    let links = |index: u32| if index < size { links(index) } else { None };
+    // Returns the position of node, given the positions of its ancestors
+    let number = |positions: &[(u32, u32)], node: u32, archetypeId: u32, parent: u32| {
+        let stem = |archetypeId| archetypeId == STEM_ARCHETYPE_INDEX;
+        match links(parent) {
+            None => (0, 1),
+            Some((parentArchetypeId, _, firstChild)) => {
+                let (rank, phytomer) = positions[parent as usize];
+                match (stem(parentArchetypeId), firstChild == node) {
+                    // The first stem segment on a stem segment continues its axis
+                    (true, true) if stem(archetypeId) => (rank, phytomer + 1),
+                    // Everything else growing from a stem segment branches off it
+                    (true, false) => (rank + 1, 1),
+                    // Stems growing out of the apex or other organs start an axis of the same
+                    // rank
+                    _ => (rank, 1),
+                }
+            }
+        }
+    };
+    let mut positions = vec![UNNUMBERED; size as usize];
+    // Ancestors that have to be numbered before a node, whenever a node comes before its parent
+    let mut path = Vec::new();
+    for index in 0..size {
+        if positions[index as usize] != UNNUMBERED {
+            continue;
+        }
+        let Some((archetypeId, parent, _)) = links(index) else {
+            positions[index as usize] = (0, 0);
+            continue;
+        };
+        // Walk up to the nearest ancestor that has been numbered
+        let mut current = (index, archetypeId, parent);
+        loop {
+            path.push(current);
+            let parent = current.2;
+            if positions.get(parent as usize) != Some(&UNNUMBERED) {
+                break;
+            }
+            match links(parent) {
+                Some((archetypeId, grandparent, _)) => current = (parent, archetypeId, grandparent),
+                None => break,
+            }
+        }
+        while let Some((node, archetypeId, parent)) = path.pop() {
+            positions[node as usize] = number(&positions, node, archetypeId, parent);
+        }
+    }
+    positions
+}
+
+impl NodeBuffer {
+    /// Returns the rank and phytomer number of the metamer every stem segment is the internode
+    /// of, indexed by node. Other nodes get the rank of the axis they grow on, and garbage slots
+    /// (0, 0)
+    pub fn metamer_positions(&self) -> Vec<(u32, u32)> {
+        let nodes = self.columns();
+        number_metamers(self.size(), |index| {
+            let i = index as usize;
+            (nodes.status[i] != STATUS_GARBAGE).then(|| {
+                (
+                    nodes.archetypeId[i],
+                    nodes.parentIndex[i],
+                    nodes.firstChildIndex[i],
+                )
+            })
+        })
+    }
+    /// Groups the nodes of every plant into metamers, numbered along their axes, see
+    /// metamer_positions. Every stem segment is the internode of one metamer, and metamers are
+    /// returned tree by tree in depth first order
+    pub fn metamers(&self) -> Vec<Metamer> {
+        let nodes = self.columns();
+        let positions = self.metamer_positions();
+        let mut metamers = Vec::new();
+        for root in self.roots() {
+            for index in self.depth_first(root) {
+                if nodes.archetypeId[index as usize] != STEM_ARCHETYPE_INDEX {
+                    continue;
+                }
+                let plant = nodes.plant_handle(index as usize);
+                let (rank, phytomer) = positions[index as usize];
+                let mut metamer = Metamer::new(index, plant, rank, phytomer);
+                for (k, child) in self.children(index).enumerate() {
+                    match nodes.archetypeId[child as usize] {
+                        STEM_ARCHETYPE_INDEX if k == 0 => (),
+                        STEM_ARCHETYPE_INDEX => metamer.branches.push(child),
+                        _ if k == 0 => metamer.apex = child,
+                        LEAF_ARCHETYPE_INDEX => metamer.leaves.push(child),
+                        GROWING_BUD_ARCHETYPE_INDEX => metamer.axillaryBuds.push(child),
+                        FLOWER_ARCHETYPE_INDEX | FRUIT_ARCHETYPE_INDEX => {
+                            metamer.flowers.push(child)
+                        }
+                        _ => (),
+                    }
+                }
+                metamers.push(metamer);
+            }
+        }
+        metamers
+    }
+
+    /// Groups the nodes of plant into metamers, see metamers
+    pub fn metamers_of_plant(&self, plant: PlantHandle) -> Vec<Metamer> {
+        let mut metamers = self.metamers();
+        metamers.retain(|metamer| metamer.plant == plant);
+        metamers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    /// Builds a main stem of three segments ending in a bud, with a branch of two segments ending
+    /// in a leaf growing from the second one. Returns the buffer and the indices of the main stem
+    /// segments followed by those of the branch
+    fn branched(plant: PlantHandle) -> (NodeBuffer, [u32; 5]) {
+        let mut node_buffer = NodeBuffer::new(16);
+        let main = stem(&mut node_buffer, plant, 3);
+        let segments: Vec<u32> = node_buffer.depth_first(main).collect();
+        let apex = organ(&mut node_buffer, plant, GROWING_BUD_ARCHETYPE_INDEX, 0.0);
+        node_buffer.add_child(segments[2], apex);
+        for organ_archetype in [LEAF_ARCHETYPE_INDEX, GROWING_BUD_ARCHETYPE_INDEX] {
+            let lateral = organ(&mut node_buffer, plant, organ_archetype, 1.0);
+            node_buffer.add_child(segments[0], lateral);
+        }
+
+        let lower_branch = organ(&mut node_buffer, plant, STEM_ARCHETYPE_INDEX, 1.0);
+        let upper_branch = organ(&mut node_buffer, plant, STEM_ARCHETYPE_INDEX, 0.0);
+        node_buffer.add_child(segments[1], lower_branch);
+        node_buffer.add_child(lower_branch, upper_branch);
+        let leaf = organ(&mut node_buffer, plant, LEAF_ARCHETYPE_INDEX, -1.0);
+        node_buffer.add_child(segments[1], leaf);
+        let fruit = organ(&mut node_buffer, plant, FRUIT_ARCHETYPE_INDEX, -1.0);
+        node_buffer.add_child(lower_branch, fruit);
+        let leaf = organ(&mut node_buffer, plant, LEAF_ARCHETYPE_INDEX, 1.0);
+        node_buffer.add_child(upper_branch, leaf);
+        (
+            node_buffer,
+            [
+                segments[0],
+                segments[1],
+                segments[2],
+                lower_branch,
+                upper_branch,
+            ],
+        )
+    }
+
+    #[test]
+    fn metamers_are_numbered_along_their_axes() {
+        let (_, plant) = living_plant();
+        let (node_buffer, [s1, s2, s3, b1, b2]) = branched(plant);
+        // Free slots are handed out from the top of the buffer down, so children come before
+        // their parents
+        assert!(b2 < b1);
+        let positions = node_buffer.metamer_positions();
+        assert_eq!(positions[s1 as usize], (0, 1));
+        assert_eq!(positions[s2 as usize], (0, 2));
+        assert_eq!(positions[s3 as usize], (0, 3));
+        assert_eq!(positions[b1 as usize], (1, 1));
+        assert_eq!(positions[b2 as usize], (1, 2));
+
+        let metamers = node_buffer.metamers();
+        assert_eq!(metamers.len(), 5);
+        for metamer in metamers.iter() {
+            let position = (metamer.rank, metamer.phytomer);
+            assert_eq!(position, positions[metamer.internode as usize]);
+        }
+        let metamer = |internode| {
+            metamers
+                .iter()
+                .find(|metamer| metamer.internode == internode)
+                .unwrap()
+        };
+        assert_eq!(metamer(s2).branches, [b1]);
+        assert!(metamer(s3).is_top());
+        assert!(metamer(b2).is_top());
+        assert!(!metamer(s2).is_top());
+        assert_eq!(metamer(s1).leaves.len(), 1);
+        assert_eq!(metamer(s1).axillaryBuds.len(), 1);
+        assert_eq!(metamer(b1).flowers.len(), 1);
+    }
+
+    #[test]
+    fn stats_count_the_organs_of_every_metamer() {
+        let (_, plant) = living_plant();
+        let (node_buffer, _) = branched(plant);
+        let stats = MetamerStats::new(&node_buffer.metamers(), &node_buffer);
+        assert_eq!(stats.metamers, 5);
+        assert_eq!(stats.leaves, 2);
+        assert_eq!(stats.axillaryBuds, 1);
+        assert_eq!(stats.flowers, 1);
+        assert_eq!(stats.maxPhytomer, 3);
+        assert_eq!(stats.perRank, [3, 2]);
+        assert_eq!(stats.meanInternodeLength, 1.0);
+    }
+
+    #[test]
+    fn metamers_of_plant_leave_out_other_plants() {
+        let (_, [first, second]) = living_plants([[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]]);
+        let (mut node_buffer, _) = branched(first);
+        stem(&mut node_buffer, second, 4);
+        assert_eq!(node_buffer.metamers().len(), 9);
+        let metamers = node_buffer.metamers_of_plant(second);
+        assert_eq!(metamers.len(), 4);
+        assert!(metamers.iter().all(|metamer| metamer.plant == second));
+    }
+
+    #[test]
+    fn branches_grow_shorter_than_their_axis() {
+        let archetype_table = ArchetypeTable::new();
+        let (mut plant_buffer, plant) = living_plant();
+        let (mut node_buffer, [s1, _, _, b1, b2]) = branched(plant);
+        for index in node_buffer.live_indices().collect::<Vec<_>>() {
+            node_buffer.columns_mut().length[index as usize] = 0.01;
+            node_buffer.columns_mut().radius[index as usize] = 0.001;
+        }
+        // Keep the buds from dividing
+        for index in node_buffer.live_indices().collect::<Vec<_>>() {
+            if node_buffer.columns().archetypeId[index as usize] == GROWING_BUD_ARCHETYPE_INDEX {
+                node_buffer.columns_mut().status[index as usize] = STATUS_DORMANT;
+            }
+        }
+        let rule = TaperedElongation::new();
+        for _ in 0..2000 {
+            node_buffer
+                .update_all(&archetype_table, &mut plant_buffer, &rule)
+                .unwrap();
+        }
+        let length = |index: u32| node_buffer.get(index).length;
+        assert!((length(s1) - rule.final_length(0, 1)).abs() < 1.0e-4);
+        for branch in [b1, b2] {
+            assert!((length(branch) - rule.final_length(1, 1)).abs() < 1.0e-4);
+        }
+        assert!((rule.final_length(1, 1) - rule.rankFactor * rule.maxLength).abs() < 1.0e-6);
+    }
+}
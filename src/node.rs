@@ -2,441 +2,1354 @@
 #![allow(unused_imports)]
 #![allow(unused_variables)]
 #![allow(non_snake_case)]
-use cgmath::{Deg, InnerSpace, Matrix4, Rad, Transform, Vector3, Vector4, One};
+use cgmath::{
+    Deg, InnerSpace, Matrix3, Matrix4, One, Quaternion, Rad, Rotation, Rotation3, Transform,
+    Vector3, Vector4,
+};
 
 use super::archetype::*;
+use super::color::*;
+use super::cylinder::*;
+use super::error::*;
+use super::leaf::*;
+use super::metamer::*;
 use super::vertex::mVertex as Vertex;
+use std::f32::consts::PI;
+use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 
 use super::plant::*;
+use super::slot::*;
+use super::util::*;
 
+/// Refers to a node in a NodeBuffer. The handle goes stale once the node is freed, even if
+/// its slot is reused by another node
+pub type NodeHandle = Handle<Node>;
+
+/// Holds the nodes of every plant. Allocation is done by the underlying SlotBuffer, which the
+/// NodeBuffer dereferences to. Nodes are stored split into columns, see NodeColumns
 #[derive(Clone)]
 pub struct NodeBuffer {
-    node_list: Vec<Node>,
-    free_stack: Vec<u32>,
-    free_ptr: u32,
-    max_size: u32,
+    node_list: SlotBuffer<NodeColumns>,
 }
 
-fn perpendicular_vector(vec: Vector3<f32>) -> Vector3<f32> {
-    // The cross product with itself will be zero, so we have 2 options
-    if vec == Vector3::unit_x() {
-        vec.cross(Vector3::unit_y())
-    } else {
-        vec.cross(Vector3::unit_x())
+impl Deref for NodeBuffer {
+    type Target = SlotBuffer<NodeColumns>;
+
+    fn deref(&self) -> &SlotBuffer<NodeColumns> {
+        &self.node_list
     }
-    .normalize()
 }
 
-fn cylgen(
-    source_loc: Vector3<f32>,
-    end_loc: Vector3<f32>,
-    radius: f32,
-    color1: [f32; 4],
-    color2: [f32; 4],
-) -> Vec<Vertex> {
-    let vec = end_loc - source_loc;
-    let mut hexvec = perpendicular_vector(vec) * radius;
-    let mut face1: Vec<Vector3<f32>> = Vec::new();
-    let mut face2: Vec<Vector3<f32>> = Vec::new();
-    for i in 0..6 {
-        face1.push(hexvec + source_loc);
-        face2.push(hexvec + end_loc);
-        hexvec =
-            (Matrix4::from_axis_angle(vec.normalize(), Deg(60.0)) * hexvec.extend(1.0)).truncate();
-    }
-
-    let mut vertex_list: Vec<Vertex> = Vec::with_capacity(18);
-    for i in 0..6 {
-        vertex_list.push(Vertex {
-            loc: face1[i].into(),
-            color: color1,
-        });
-        //push next in line
-        vertex_list.push(Vertex {
-            loc: face1[(i + 1) % 6].into(),
-            color: color1,
-        });
-        //push one from top
-        vertex_list.push(Vertex {
-            loc: face2[i].into(),
-            color: color2,
-        });
-    }
-    for i in 0..6 {
-        vertex_list.push(Vertex {
-            loc: face2[i].into(),
-            color: color2,
-        });
-        //push next in line
-        vertex_list.push(Vertex {
-            loc: face2[(i + 1) % 6].into(),
-            color: color2,
-        });
-        //push one from top
-        vertex_list.push(Vertex {
-            loc: face1[(i + 1) % 6].into(),
-            color: color1,
-        });
-    }
-    vertex_list
+impl DerefMut for NodeBuffer {
+    fn deref_mut(&mut self) -> &mut SlotBuffer<NodeColumns> {
+        &mut self.node_list
+    }
 }
 
 /// Returns the delta logistic growth
-fn logisticDelta(current: f32, max: f32, scale: f32) -> f32 {
+pub fn logisticDelta(current: f32, max: f32, scale: f32) -> f32 {
     current * (max - current) * scale
 }
 
-fn leafgen(
-    source_loc: Vector3<f32>,
-    end_loc: Vector3<f32>,
-    up: Vector3<f32>,
-    width: f32,
-    color1: [f32; 4],
-    color2: [f32; 4],
-) -> Vec<Vertex> {
-    let mut vertex_list: Vec<Vertex> = Vec::new();
-
-    // The vector between the source and end
-    let vec = end_loc - source_loc;
-    // This is the horizontal part of the leaf
-    let perpvec = vec.cross(up).normalize() * (width / 2.0);
-
-    let point1 = source_loc - perpvec;
-    let point2 = source_loc + perpvec;
-    let point3 = end_loc - perpvec;
-    let point4 = end_loc + perpvec;
-
-    //push first triangle
-    vertex_list.push(Vertex {
-        loc: point1.into(),
-        color: color1,
-    });
-    vertex_list.push(Vertex {
-        loc: point2.into(),
-        color: color1,
-    });
-    vertex_list.push(Vertex {
-        loc: point3.into(),
-        color: color2,
-    });
-
-    //push second triangle
-    vertex_list.push(Vertex {
-        loc: point3.into(),
-        color: color2,
-    });
-    vertex_list.push(Vertex {
-        loc: point4.into(),
-        color: color2,
-    });
-    vertex_list.push(Vertex {
-        loc: point2.into(),
-        color: color1,
-    });
-
-    vertex_list
-}
-
 impl NodeBuffer {
-
+    /// Creates a buffer with room for size nodes, which grows whenever it runs out of room
     pub fn new(size: u32) -> NodeBuffer {
-        if size == 0 || size == INVALID_INDEX {
-            panic!("invalid size for node buffer")
-        }
         NodeBuffer {
-            node_list: vec![Node::new(); size as usize], // Create list with default nodes
-            free_stack: (0..size).collect(),             // Create list of all free node locations
-            free_ptr: size,                              // The current pointer to the active stack
-            max_size: size, // The maximum size to which the stack may grow
+            node_list: SlotBuffer::new(size),
         }
     }
 
-    pub fn get(&self, index: u32) -> Node {
-        self.node_list[index as usize].clone()
-    }
-
-    pub fn set(&mut self, index: u32, node: Node) {
-        self.node_list[index as usize] = node.clone();
-    }
-
-    /// Returns the index of a free spot in the array (user needs to mark the spot as not garbage)
-    pub fn alloc(&mut self) -> u32 {
-        if self.free_ptr == 0 {
-            panic!("No Memory Left In NodeBuffer");
-        } else {
-            self.free_ptr = self.free_ptr - 1;
-            self.free_stack[self.free_ptr as usize]
+    /// Creates a buffer with room for size nodes, which grows whenever it runs out of room, but
+    /// never beyond max_size nodes
+    pub fn with_max_size(size: u32, max_size: u32) -> NodeBuffer {
+        NodeBuffer {
+            node_list: SlotBuffer::with_max_size(size, max_size),
         }
     }
 
-    pub fn alloc_insert(&mut self, node: Node) {
-        let index = self.alloc();
-        self.set(index, node.clone());
+    /// Returns the node columns, for passes that only need a few fields of every node
+    pub fn columns(&self) -> &NodeColumns {
+        self.node_list.storage()
     }
 
-    /// Marks an index in the array as free to use, marks any node as garbage
-    pub fn free(&mut self, index: u32) {
-        self.node_list[index as usize].status = STATUS_GARBAGE;
-        if self.free_ptr == self.max_size {
-            panic!("Free Stack Full (This should not happen)");
-        } else {
-            self.free_stack[self.free_ptr as usize] = index;
-            self.free_ptr = self.free_ptr + 1;
-        }
+    /// Returns the node columns mutably, for passes that only need a few fields of every node
+    pub fn columns_mut(&mut self) -> &mut NodeColumns {
+        self.node_list.storage_mut()
     }
 
-    /// Rreturns maximum size that the node list could grow to.
-    pub fn size(&self) -> u32 {
-        self.max_size
-    }
-
-    /// Returns the current size that the node buffer is at
-    pub fn current_size(&self) -> u32 {
-        self.max_size - self.free_ptr
-    }
+    /// Generates a list of vertexes to be rendered, with nodes coloured as colors asks. The world
+    /// frames are brought up to date first
+    pub fn gen_vertex(
+        &mut self,
+        plant_buffer: &PlantBuffer,
+        archetype_table: &ArchetypeTable,
+        style: &CylinderStyle,
+        colors: &NodeColors,
+    ) -> Vec<Vertex> {
+        self.update_frames(plant_buffer);
+        let palette = self.node_colors(colors, plant_buffer, archetype_table);
 
-    /// Generates a list of vertexes to be rendered
-    pub fn gen_vertex(&self, plant_buffer: &PlantBuffer) -> Vec<Vertex> {
         //Vector to hold all new vertexes
         let mut vertex_list = Vec::new();
-
-        //search for root node (null parent, visible)
-        for node_index in 0..self.max_size {
-            let node = &self.node_list[node_index as usize];
-            // If its a root node
-            if node.status != STATUS_GARBAGE && node.parentIndex == INVALID_INDEX {
-                // call gen_node_vertex
-                let plant = plant_buffer.get(node.plantId);
+        // Walk every tree from its root, parents before children
+        for root in self.roots() {
+            for node_index in self.depth_first(root) {
                 vertex_list.append(&mut self.gen_node_vertex(
-                    tov(plant.location),
-                    Matrix4::one(),
                     node_index,
+                    archetype_table,
+                    style,
+                    palette[node_index as usize],
                 ));
             }
         }
         vertex_list
     }
-    /// Internal recursive algorithm that traverses tree structure
+
+    /// Returns true if node_index is drawn as a generalised cylinder
+    fn is_tube(&self, node_index: u32) -> bool {
+        let nodes = self.columns();
+        nodes.visible[node_index as usize] == 1
+            && nodes.archetypeId[node_index as usize] != LEAF_ARCHETYPE_INDEX
+    }
+
+    /// Returns the direction node_index points in, as of the last call to update_frames
+    fn axis(&self, node_index: u32) -> Vector3<f32> {
+        self.frame(node_index)
+            .rotation
+            .rotate_vector(Vector3::unit_y())
+    }
+
+    /// Generates the vertexes of a single node from its world frame
     fn gen_node_vertex(
         &self,
-        source_loc: Vector3<f32>,
-        parent_rotation: Matrix4<f32>,
         node_index: u32,
+        archetype_table: &ArchetypeTable,
+        style: &CylinderStyle,
+        color: [f32; 4],
     ) -> Vec<Vertex> {
-        let mut vertex_list = Vec::new();
-        let node = self.node_list[node_index as usize];
-        // The rotation of this node
-        let total_rotation = parent_rotation * tomat(node.transformation);
-        // The endpoint in space where this node ends
-        let end_loc = source_loc + total_rotation.transform_vector(Vector3::unit_y() * node.length);
-        if node.visible == 1 {
-            if node.archetypeId == LEAF_ARCHETYPE_INDEX {
-                vertex_list.append(&mut leafgen(
-                    source_loc,
-                    end_loc,
-                    Vector3::unit_y(),
-                    node.radius,
-                    [0.0, 1.0, 0.0, 1.0],
-                    [1.0, 1.0, 0.0, 1.0],
-                ));
+        let node = self.get(node_index);
+        if node.visible != 1 {
+            return Vec::new();
+        }
+        let frame = self.frame(node_index);
+        // Nodes of unknown archetypes are drawn with the default shape
+        let default = Archetype::new();
+        let archetype = archetype_table.get(node.archetypeId).unwrap_or(&default);
+        if node.archetypeId == LEAF_ARCHETYPE_INDEX {
+            let stem_axis = match node.parentIndex {
+                INVALID_INDEX => None,
+                parent => Some(self.axis(parent)),
+            };
+            leaf_mesh(
+                &frame,
+                stem_axis,
+                node.length,
+                node.radius,
+                archetype,
+                color,
+                color,
+            )
+        } else {
+            // The cylinder tapers towards the segment continuing its axis, and when joined
+            // shares the ring at the joint with it
+            let axis = self.axis(node_index);
+            let joined = style.ends == Ends::Joined;
+            let parent = node.parentIndex;
+            let continues_parent = parent != INVALID_INDEX
+                && self.columns().firstChildIndex[parent as usize] == node_index
+                && self.is_tube(parent);
+            let base_normal = if joined && continues_parent {
+                joint_normal(self.axis(parent), axis)
             } else {
-                vertex_list.append(&mut cylgen(
-                    source_loc,
-                    end_loc,
-                    node.radius,
-                    [0.0, 0.0, 0.0, 1.0],
-                    [0.0, 1.0, 0.0, 1.0],
-                ));
+                axis
+            };
+            let child = node.firstChildIndex;
+            let continued = child != INVALID_INDEX && self.is_tube(child);
+            let tip_radius = if continued {
+                self.columns().radius[child as usize]
+            } else {
+                node.radius
+            };
+            let tip_normal = if joined && continued {
+                joint_normal(axis, self.axis(child))
+            } else {
+                axis
+            };
+            let base = Ring::new(
+                frame.base,
+                base_normal,
+                node.radius,
+                color,
+                &style.resolution,
+            );
+            let tip = Ring::new(frame.tip, tip_normal, tip_radius, color, &style.resolution);
+            let capped = style.ends != Ends::Open;
+            tube(
+                &base,
+                &tip,
+                capped && !(joined && continues_parent),
+                capped && !(joined && continued),
+            )
+        }
+    }
+
+    /// Recomputes the world frames of all nodes that have moved since the last time this was
+    /// called, and of everything growing out of them. Frames of nodes that haven't moved are kept.
+    /// Roots start at the location of their plant. Trees whose root has no plant are skipped, and
+    /// keep their old frames
+    pub fn update_frames(&mut self, plant_buffer: &PlantBuffer) {
+        let mut stack = Vec::new();
+        for ni in 0..self.size() {
+            let nodes = self.columns();
+            if !nodes.moved[ni as usize] || self.is_garbage(ni) {
+                continue;
+            }
+            // Start from the highest moved ancestor, which has to be recomputed anyway
+            let mut top = ni;
+            let mut parent = nodes.parentIndex[ni as usize];
+            while parent != INVALID_INDEX && nodes.moved[parent as usize] {
+                top = parent;
+                parent = nodes.parentIndex[parent as usize];
+            }
+            let (base, parent_rotation) = match parent {
+                INVALID_INDEX => match plant_buffer.try_get(nodes.plantId[top as usize]) {
+                    Ok(plant) => (tov(plant.location), Quaternion::one()),
+                    // There is nowhere to put a root that doesn't belong to any plant
+                    Err(_) => continue,
+                },
+                _ => {
+                    let frame = nodes.frame[parent as usize];
+                    (frame.tip, frame.rotation)
+                }
+            };
+
+            // Walk the subtree with an explicit stack, so that deep plants can't overflow
+            let nodes = self.columns_mut();
+            stack.push((top, base, parent_rotation));
+            while let Some((index, base, parent_rotation)) = stack.pop() {
+                let i = index as usize;
+                let rotation = renormalize(parent_rotation * nodes.rotation(i));
+                let tip = base + rotation.rotate_vector(Vector3::unit_y() * nodes.length[i]);
+                nodes.frame[i] = WorldFrame {
+                    base,
+                    tip,
+                    rotation,
+                };
+                nodes.moved[i] = false;
+                let mut child = nodes.firstChildIndex[i];
+                while child != INVALID_INDEX {
+                    stack.push((child, tip, rotation));
+                    child = nodes.nextSiblingIndex[child as usize];
+                }
             }
         }
-        // Get child vertex_list, and add it to our own
-        if node.leftChildIndex != INVALID_INDEX {
-            vertex_list.append(&mut self.gen_node_vertex(
-                end_loc,
-                total_rotation,
-                node.leftChildIndex,
-            ));
-        }
-        // Do the same for the right node
-        if node.rightChildIndex != INVALID_INDEX {
-            vertex_list.append(&mut self.gen_node_vertex(
-                end_loc,
-                total_rotation,
-                node.rightChildIndex,
-            ));
-        }
-        // Return the list
-        vertex_list
     }
 
-    /// Sets the left child of parent to child, and if child is not invalid, sets its parent to the parent
-    pub fn set_left_child(&mut self, parent: u32, child: u32) {
-        self.node_list[parent as usize].leftChildIndex = child;
-        if child != INVALID_INDEX {
-            self.node_list[child as usize].parentIndex = parent;
+    /// Returns an error unless node_index refers to a node that is in use
+    pub fn check_live(&self, node_index: u32) -> Result<()> {
+        if node_index >= self.size() {
+            Err(Error::InvalidIndex(node_index))
+        } else if self.is_garbage(node_index) {
+            Err(Error::GarbageNode(node_index))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the world frame of node_index as of the last call to update_frames
+    pub fn frame(&self, node_index: u32) -> WorldFrame {
+        self.columns().frame[node_index as usize]
+    }
+
+    /// Adds child as the last child of parent, and sets its parent to the parent. The child must
+    /// not already be the child of another node, see detach
+    pub fn add_child(&mut self, parent: u32, child: u32) {
+        let nodes = self.columns_mut();
+        nodes.parentIndex[child as usize] = parent;
+        nodes.nextSiblingIndex[child as usize] = INVALID_INDEX;
+        nodes.invalidate(child as usize);
+        match nodes.last_child(parent as usize) {
+            INVALID_INDEX => nodes.firstChildIndex[parent as usize] = child,
+            last => nodes.nextSiblingIndex[last as usize] = child,
+        }
+    }
+
+    /// Adds child as the first child of parent, in front of any children it already has, so that
+    /// child continues the parent's axis. The child must not already be the child of another node,
+    /// see detach
+    pub fn add_first_child(&mut self, parent: u32, child: u32) {
+        let nodes = self.columns_mut();
+        nodes.parentIndex[child as usize] = parent;
+        nodes.nextSiblingIndex[child as usize] = nodes.firstChildIndex[parent as usize];
+        nodes.firstChildIndex[parent as usize] = child;
+        nodes.invalidate(child as usize);
+    }
+
+    /// Cuts the link between node_index and its parent, making node_index the root of its own tree
+    pub fn detach(&mut self, node_index: u32) {
+        let nodes = self.columns_mut();
+        let parent = nodes.parentIndex[node_index as usize];
+        if parent == INVALID_INDEX {
+            return;
+        }
+        let next = nodes.nextSiblingIndex[node_index as usize];
+        if nodes.firstChildIndex[parent as usize] == node_index {
+            nodes.firstChildIndex[parent as usize] = next;
+        } else {
+            // Find the sibling before the node and link it past the node
+            let mut sibling = nodes.firstChildIndex[parent as usize];
+            while sibling != INVALID_INDEX {
+                if nodes.nextSiblingIndex[sibling as usize] == node_index {
+                    nodes.nextSiblingIndex[sibling as usize] = next;
+                    break;
+                }
+                sibling = nodes.nextSiblingIndex[sibling as usize];
+            }
+        }
+        nodes.parentIndex[node_index as usize] = INVALID_INDEX;
+        nodes.nextSiblingIndex[node_index as usize] = INVALID_INDEX;
+        nodes.invalidate(node_index as usize);
+    }
+
+    /// Detaches node_index from its parent, and frees it along with all of its descendants.
+    /// Returns an error without changing anything if node_index isn't a live node
+    pub fn free_subtree(&mut self, node_index: u32) -> Result<()> {
+        self.check_live(node_index)?;
+        self.detach(node_index);
+        let subtree: Vec<u32> = self.depth_first(node_index).collect();
+        for index in subtree {
+            self.free(index);
+            self.set(index, Node::new());
+        }
+        Ok(())
+    }
+
+    /// Prunes the branch at node_index, freeing everything growing out of it. The node itself is
+    /// kept as the cut end of the branch. Returns an error if node_index isn't a live node
+    pub fn prune(&mut self, node_index: u32) -> Result<()> {
+        self.check_live(node_index)?;
+        let children: Vec<u32> = self.children(node_index).collect();
+        for child in children {
+            self.free_subtree(child)?;
+        }
+        Ok(())
+    }
+
+    /// Frees every node belonging to plant, and then the plant itself. Nodes of other plants, or
+    /// left behind by an earlier plant in the same slot, that were attached to the removed plant
+    /// are left as the roots of their own trees. Returns an error without changing anything if the
+    /// handle is stale
+    pub fn remove_plant(
+        &mut self,
+        plant_buffer: &mut PlantBuffer,
+        plant: PlantHandle,
+    ) -> Result<()> {
+        plant_buffer.get_handle(plant).ok_or(Error::StaleHandle)?;
+        let nodes = self.columns();
+        let owned: Vec<u32> = self
+            .live_indices()
+            .filter(|&ni| nodes.plant_handle(ni as usize) == plant)
+            .collect();
+        for ni in owned {
+            let children: Vec<u32> = self.children(ni).collect();
+            for child in children {
+                if self.columns().plant_handle(child as usize) != plant {
+                    self.detach(child);
+                }
+            }
+            self.detach(ni);
+            self.free(ni);
+            self.set(ni, Node::new());
         }
+        plant_buffer.free(plant.index);
+        Ok(())
     }
 
-    /// Sets the right child of parent to child, and if child is not invalid, sets its parent to the parent
-    pub fn set_right_child(&mut self, parent: u32, child: u32) {
-        self.node_list[parent as usize].rightChildIndex = child;
-        if child != INVALID_INDEX {
-            self.node_list[child as usize].parentIndex = parent;
+    /// Defragments the buffer, moving all nodes to the front of it ordered by plant, and within
+    /// each tree in depth first order, so that the nodes of a plant are next to each other. Returns
+    /// a table mapping every old node index to its new one, or to INVALID_INDEX for free slots. All
+    /// existing node handles go stale
+    pub fn compact(&mut self) -> Vec<u32> {
+        let mut roots: Vec<u32> = self.roots().collect();
+        roots.sort_by_key(|&root| self.columns().plantId[root as usize]);
+        let mut order = Vec::with_capacity(self.current_size() as usize);
+        for root in roots {
+            order.extend(self.depth_first(root));
+        }
+        // Nodes that can't be reached from any root, which only happens if the links between
+        // nodes are broken, are kept at the end rather than lost
+        if order.len() < self.current_size() as usize {
+            let mut placed = vec![false; self.size() as usize];
+            for &index in order.iter() {
+                placed[index as usize] = true;
+            }
+            let unreached: Vec<u32> = self
+                .live_indices()
+                .filter(|&index| !placed[index as usize])
+                .collect();
+            order.extend(unreached);
+        }
+
+        let remap = self.reorder(&order);
+        let moved = |index: u32| match index {
+            INVALID_INDEX => INVALID_INDEX,
+            _ => remap[index as usize],
+        };
+        let nodes = self.columns_mut();
+        for i in 0..order.len() {
+            nodes.parentIndex[i] = moved(nodes.parentIndex[i]);
+            nodes.firstChildIndex[i] = moved(nodes.firstChildIndex[i]);
+            nodes.nextSiblingIndex[i] = moved(nodes.nextSiblingIndex[i]);
         }
+        remap
     }
 
-    /// Divides node segment in two, allocating a new node for the upper half, with the current
-    /// node as a parent. The children are transferred to the new node. All properties of the old
-    /// node are maintained, except for children. The left child is the new node, and the right one
-    /// is left empty
-    /// returns the index of the newly created node
-    pub fn divide(&mut self, percentbreak: f32, node_index: u32) -> u32 {
+    /// Divides the segment at node_index in two at fraction of its length, which must lie strictly
+    /// between zero and one. The node becomes the lower segment, and a new node is allocated for
+    /// the upper one, continuing straight on from it. Both segments keep the age and radius of the
+    /// original, and its length and volume are shared between them in proportion. The children
+    /// argument decides which segment the children of the node end up on
+    /// returns handles to the lower and upper segments. Nothing changes if the fraction is
+    /// invalid or there is no room for the new node
+    pub fn divide(
+        &mut self,
+        fraction: f32,
+        node_index: u32,
+        children: SplitChildren,
+    ) -> Result<(NodeHandle, NodeHandle)> {
+        self.check_live(node_index)?;
+        if !(fraction > 0.0 && fraction < 1.0) {
+            return Err(Error::InvalidFraction);
+        }
         // Allocate a spot for the new node
-        let new_node_index = self.alloc();
+        let new_node_index = self.try_alloc()?;
 
         // New node shares all properties with old one
-        self.node_list[new_node_index as usize] = self.node_list[node_index as usize].clone();
-        // Set lengths so they add up to same amount TODO ensure percentbreak is less than one
-        let origlength = self.node_list[node_index as usize].length;
-        self.node_list[node_index as usize].length = percentbreak * origlength;
-        self.node_list[new_node_index as usize].length = (1.0 - percentbreak) * origlength;
-
-        //Remove any transformation from the new node
-        self.node_list[new_node_index as usize].transformation = cgmath::Matrix4::one().into();
-
-        //Join the two children of the current node on as children of the new node
-        self.set_left_child(
-            new_node_index,
-            self.node_list[node_index as usize].leftChildIndex,
-        );
-        self.set_right_child(
-            new_node_index,
-            self.node_list[node_index as usize].rightChildIndex,
-        );
+        let node = self.get(node_index);
+        self.set(new_node_index, node);
+        let (lower, upper) = (node_index as usize, new_node_index as usize);
+        let nodes = self.columns_mut();
+        nodes.length[lower] = fraction * node.length;
+        nodes.volume[lower] = fraction * node.volume;
+        nodes.invalidate(lower);
+        nodes.length[upper] = (1.0 - fraction) * node.length;
+        nodes.volume[upper] = (1.0 - fraction) * node.volume;
 
-        // Join the new node as the left child of the current one, and emptying the right one
-        self.set_left_child(node_index, new_node_index);
-        self.set_right_child(node_index, INVALID_INDEX);
-        //Return the index of the new node created
-        new_node_index
-    }
-
-    /// Does a nodeupdatenode on all nodes within the buffer that are not garbage
-    pub fn update_all(&mut self) {
-        for ni in 0..self.max_size {
-            let mut node = self.node_list[ni as usize];
-            if node.status != STATUS_GARBAGE {
-                self.node_list[ni as usize].age += 1;
-
-                match node.archetypeId {
-                    INVALID_ARCHETYPE_INDEX => (),
-                    GROWING_BUD_ARCHETYPE_INDEX => {
-                        if rand::random::<f32>() > 0.999 && node.age < 9000 {
-                            let leftchildindex = self.alloc();
-                            self.node_list[leftchildindex as usize] = node.clone();
-                            self.node_list[leftchildindex as usize].transformation =
-                                (Matrix4::from_angle_z(Rad((rand::random::<f32>() - 0.5) * 0.5))
-                                    * Matrix4::from_angle_x(Rad(
-                                        (rand::random::<f32>() - 0.5) * 0.5
-                                    )))
-                                .into();
-                            node.archetypeId = STEM_ARCHETYPE_INDEX;
-                            node.length = 0.001;
-                            self.node_list[ni as usize] = node;
-                            self.set_left_child(ni, leftchildindex);
-                            if rand::random::<f32>() > 0.99 {
-                                let rightchildindex = self.alloc();
-                                // Create new node for leaf
-                                let mut leafnode = Node::new();
-                                leafnode.archetypeId = GROWING_BUD_ARCHETYPE_INDEX;
-                                leafnode.visible = 1;
-                                leafnode.status = STATUS_ALIVE;
-                                leafnode.length = 0.001;
-                                leafnode.radius = 0.001;
-                                leafnode.transformation = (Matrix4::from_angle_z(Rad(
-                                    (rand::random::<f32>() - 0.5) * 2.0,
-                                )) * Matrix4::from_angle_x(Rad(
-                                    (rand::random::<f32>() - 0.5) * 2.0,
-                                )))
-                                .into();
-                                self.node_list[rightchildindex as usize] = leafnode;
-                                self.set_right_child(ni, rightchildindex);
-                            }
-                        }
-                    }
-                    STEM_ARCHETYPE_INDEX => {
-                        self.node_list[ni as usize].length += logisticDelta(node.length, 0.1, 1.0);
-                        self.node_list[ni as usize].radius += logisticDelta(node.radius, 0.02, 1.0);
-                    }
-                    LEAF_ARCHETYPE_INDEX => {
-                        self.node_list[ni as usize].length += logisticDelta(node.length, 0.3, 0.1);
-                        self.node_list[ni as usize].radius += logisticDelta(node.radius, 0.05, 0.5);
+        // The upper segment carries on along the axis of the lower one, so that the children
+        // it takes over keep their place in the world
+        nodes.orientation[upper] = Quaternion::one();
+        nodes.roll[upper] = 0.0;
+
+        // The first child continues the axis, so it always moves to the upper segment along with
+        // the laterals after it, unless they are kept on the lower one
+        let first = nodes.firstChildIndex[lower];
+        let kept = match children {
+            SplitChildren::LateralsLower if first != INVALID_INDEX => {
+                let laterals = nodes.nextSiblingIndex[first as usize];
+                nodes.nextSiblingIndex[first as usize] = INVALID_INDEX;
+                laterals
+            }
+            _ => INVALID_INDEX,
+        };
+        nodes.firstChildIndex[upper] = first;
+        let mut child = first;
+        while child != INVALID_INDEX {
+            nodes.parentIndex[child as usize] = new_node_index;
+            nodes.invalidate(child as usize);
+            child = nodes.nextSiblingIndex[child as usize];
+        }
+
+        // Join the new node on as the first child of the current one, in front of any laterals
+        // that were kept, as it continues the axis
+        nodes.parentIndex[upper] = node_index;
+        nodes.nextSiblingIndex[upper] = kept;
+        nodes.firstChildIndex[lower] = new_node_index;
+        nodes.invalidate(upper);
+        Ok((self.handle(node_index), self.handle(new_node_index)))
+    }
+
+    /// Does a nodeupdatenode on all nodes within the buffer that are not garbage, and updates the
+    /// carbon reserves of the plants they belong to. Buds divide once every node has been updated,
+    /// so the nodes they grow are first updated on the next tick. Nodes that can't be updated,
+    /// because they have an unknown archetype or there is no room for the nodes they grow, are
+    /// skipped, and the first such error is returned once all other nodes have been updated. Stem
+    /// segments grow by stem_growth, according to where their metamer sits on the plant
+    pub fn update_all(
+        &mut self,
+        archetype_table: &ArchetypeTable,
+        plant_buffer: &mut PlantBuffer,
+        stem_growth: &impl MetamerRule,
+    ) -> Result<()> {
+        let mut result = Ok(());
+        let positions = self.metamer_positions();
+        let mut current_plant: Option<(PlantHandle, Plant)> = None;
+        // Buds that divide this tick, which is left until all nodes have been updated so that the
+        // columns can be borrowed for the whole loop
+        let mut dividing = Vec::new();
+        // Work on the columns directly, so that garbage and dormant slots only cost a read of
+        // their status
+        let nodes = self.columns_mut();
+        // There is a position for every slot, see metamer_positions
+        for (i, &(rank, phytomer)) in positions.iter().enumerate() {
+            if nodes.status[i] == STATUS_GARBAGE {
+                continue;
+            }
+            let age = nodes.age[i];
+            nodes.age[i] += 1;
+
+            // Only living nodes grow
+            let archetypeId = nodes.archetypeId[i];
+            if nodes.status[i] != STATUS_ALIVE || archetypeId == INVALID_ARCHETYPE_INDEX {
+                continue;
+            }
+            let archetype = match archetype_table.get(archetypeId) {
+                Some(archetype) => archetype,
+                None => {
+                    result = result.and(Err(Error::UnknownArchetype(archetypeId)));
+                    continue;
+                }
+            };
+
+            // Nodes of one plant are mostly next to each other, so the plant is only written
+            // back once a node of another plant comes up
+            let plant_handle = nodes.plant_handle(i);
+            if !current_plant.is_some_and(|(handle, _)| handle == plant_handle) {
+                if let Some((handle, plant)) = current_plant {
+                    plant_buffer.set(handle.index, plant);
+                }
+                current_plant = plant_buffer
+                    .get_handle(plant_handle)
+                    .map(|plant| (plant_handle, plant));
+            }
+            // Nodes whose plant has been freed are left to die, see lifecycle::update_lifecycle
+            let plant = match current_plant.as_mut() {
+                Some((_, plant)) => plant,
+                None => continue,
+            };
+            let length = nodes.length[i];
+            let radius = nodes.radius[i];
+            // Green tissue produces carbon in proportion to its projected area, and all tissue
+            // consumes it in proportion to its volume
+            plant.carbon += archetype.photosynthesisRate * length * radius * 2.0
+                - archetype.respirationRate * nodes.volume[i];
+
+            match archetypeId {
+                GROWING_BUD_ARCHETYPE_INDEX => {
+                    if plant.age >= archetype.floweringAge
+                        && rand::random::<f32>() < archetype.floweringProbability
+                    {
+                        // The bud stops growing and becomes a flower
+                        nodes.archetypeId[i] = FLOWER_ARCHETYPE_INDEX;
+                        nodes.age[i] = 0;
+                    } else if rand::random::<f32>() > 0.999 && age < 9000 {
+                        dividing.push((i as u32, archetype));
                     }
-                    _ => println!("oof"),
                 }
+                ROOT_ARCHETYPE_INDEX => nodes.grow(i, 0.2, 0.5, 0.01, 0.5),
+                STEM_ARCHETYPE_INDEX => {
+                    let (length, radius) = stem_growth.grow(length, radius, rank, phytomer);
+                    nodes.reshape(i, length, radius);
+                }
+                LEAF_ARCHETYPE_INDEX => nodes.grow(i, 0.3, 0.1, 0.05, 0.5),
+                FLOWER_ARCHETYPE_INDEX if age >= archetype.maturationAge => {
+                    // The flower has been pollinated and sets fruit
+                    nodes.archetypeId[i] = FRUIT_ARCHETYPE_INDEX;
+                    nodes.age[i] = 0;
+                }
+                FRUIT_ARCHETYPE_INDEX => {
+                    // Fruits swell until they ripen, see reproduction::disperse_seeds
+                    nodes.radius[i] += logisticDelta(radius, 0.03, 0.5);
+                    nodes.update_volume(i);
+                }
+                _ => (),
+            }
+        }
+        if let Some((handle, plant)) = current_plant {
+            plant_buffer.set(handle.index, plant);
+        }
+        for (ni, archetype) in dividing {
+            if let Err(e) = self.divide_bud(ni, archetype) {
+                result = result.and(Err(e));
             }
         }
+        result
     }
-}
 
-fn tomat(mat: [[f32; 4]; 4]) -> Matrix4<f32> {
-    Matrix4::from_cols(
-        Vector4::from(mat[0]),
-        Vector4::from(mat[1]),
-        Vector4::from(mat[2]),
-        Vector4::from(mat[3]),
-    )
-}
+    /// Turns the growing bud at ni into a stem segment with the bud continuing on top of it, and
+    /// sometimes grows lateral buds from the node in between, as many as the archetype's
+    /// phyllotaxis places at one node. Nothing changes if there is no room for the continuing bud,
+    /// and the lateral buds are skipped if there is no room for them
+    fn divide_bud(&mut self, ni: u32, archetype: &Archetype) -> Result<()> {
+        let mut node = self.get(ni);
+        let budindex = self.try_alloc()?;
+        self.set(budindex, node);
+        self.columns_mut().firstChildIndex[budindex as usize] = INVALID_INDEX;
+        // Rotate the continuing bud about its axis, so that the next lateral
+        // is offset from this one by the divergence angle
+        self.columns_mut().orientation[budindex as usize] = renormalize(
+            Quaternion::from_angle_y(Rad(archetype.divergence() + jitter(archetype.angleJitter)))
+                * Quaternion::from_angle_z(Rad((rand::random::<f32>() - 0.5) * 0.5))
+                * Quaternion::from_angle_x(Rad((rand::random::<f32>() - 0.5) * 0.5)),
+        );
+        node.archetypeId = STEM_ARCHETYPE_INDEX;
+        node.length = 0.001;
+        node.volume = cylinder_volume(node);
+        self.set(ni, node);
+        // The continuing bud is always the first child, laterals come after it
+        self.add_first_child(ni, budindex);
 
-fn tov(v3: [f32; 3]) -> Vector3<f32> {
-    Vector3::new(v3[0], v3[1], v3[2])
+        if rand::random::<f32>() > 0.99 {
+            // Organs at one node are spread evenly around the axis
+            let count = archetype.organs_per_node();
+            for (k, index) in self.try_alloc_many(count)?.into_iter().enumerate() {
+                let azimuth = 2.0 * PI * k as f32 / count as f32;
+                self.set(index, lateral_bud(archetype, node.plant_handle(), azimuth));
+                self.add_child(ni, index);
+            }
+        }
+        Ok(())
+    }
 }
 
-fn to3(v: Vector3<f32>) -> [f32; 3] {
-    [v.x, v.y, v.z]
+/// Returns the volume of the cylinder making up the node
+pub fn cylinder_volume(node: Node) -> f32 {
+    PI * node.radius * node.radius * node.length
 }
 
-fn scale3(a: [f32; 3], scalar: f32) -> [f32; 3] {
-    [a[0] * scalar, a[1] * scalar, a[2] * scalar]
+/// Returns a random angle in the range [-max, max]
+fn jitter(max: f32) -> f32 {
+    (rand::random::<f32>() * 2.0 - 1.0) * max
 }
 
-fn add3(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
-    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+/// Creates a new growing bud leaving its parent axis at the archetype's insertion angle,
+/// rotated about the parent axis by azimuth
+pub fn lateral_bud(archetype: &Archetype, plant: PlantHandle, azimuth: f32) -> Node {
+    let mut budnode = Node::new();
+    budnode.archetypeId = GROWING_BUD_ARCHETYPE_INDEX;
+    budnode.set_plant(plant);
+    budnode.visible = 1;
+    budnode.status = STATUS_ALIVE;
+    budnode.length = 0.001;
+    budnode.radius = 0.001;
+    budnode.set_orientation(
+        Quaternion::from_angle_y(Rad(azimuth + jitter(archetype.angleJitter)))
+            * Quaternion::from_angle_z(Rad(
+                archetype.insertionAngle + jitter(archetype.angleJitter)
+            )),
+    );
+    budnode
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct Node {
-    pub leftChildIndex: u32,
-    pub rightChildIndex: u32,
+    pub firstChildIndex: u32,
+    pub nextSiblingIndex: u32, // Next child of the same parent, children form a linked list
     pub parentIndex: u32,
     pub age: u32,
     pub archetypeId: u32,
     pub plantId: u32,
+    pub plantGeneration: u32, // Generation of the plant's slot, see PlantHandle
     pub status: u32,
     pub visible: u32,
     pub length: f32,
     pub radius: f32, // also can be width
     pub volume: f32,
-    pub transformation: [[f32; 4]; 4],
+    pub orientation: [f32; 4], // Unit quaternion (x, y, z, w), the rotation relative to the parent
+    pub roll: f32, // Rotation in radians about the node's own axis, applied after orientation
 }
 
 impl Node {
     // Node has some dummy variables and this function makes it easier to create a default instance
     pub fn new() -> Node {
         Node {
-            leftChildIndex: INVALID_INDEX,
-            rightChildIndex: INVALID_INDEX,
+            firstChildIndex: INVALID_INDEX,
+            nextSiblingIndex: INVALID_INDEX,
             parentIndex: INVALID_INDEX,
             age: 0,
             archetypeId: INVALID_ARCHETYPE_INDEX,
             plantId: INVALID_INDEX,
+            plantGeneration: 0,
             status: STATUS_GARBAGE,
             visible: 0,
             length: 0.0,
             radius: 0.0, // also can be width
             volume: 0.0,
-            transformation: Matrix4::one().into(),
+            orientation: Quaternion::one().into(),
+            roll: 0.0,
+        }
+    }
+
+    /// Returns a handle to the plant this node belongs to
+    pub fn plant_handle(&self) -> PlantHandle {
+        Handle::new(self.plantId, self.plantGeneration)
+    }
+
+    /// Makes this node belong to plant
+    pub fn set_plant(&mut self, plant: PlantHandle) {
+        self.plantId = plant.index;
+        self.plantGeneration = plant.generation;
+    }
+
+    /// Returns the rotation of this node relative to its parent, including its roll
+    pub fn rotation(&self) -> Quaternion<f32> {
+        renormalize(Quaternion::from(self.orientation) * Quaternion::from_angle_y(Rad(self.roll)))
+    }
+
+    /// Sets the orientation of this node relative to its parent, renormalising it
+    pub fn set_orientation(&mut self, orientation: Quaternion<f32>) {
+        self.orientation = renormalize(orientation).into();
+    }
+
+    /// Rotates this node by rotation, which is given in the node's own frame
+    pub fn rotate(&mut self, rotation: Quaternion<f32>) {
+        self.set_orientation(Quaternion::from(self.orientation) * rotation);
+    }
+
+    /// Returns the rotation of this node relative to its parent as a column major matrix, which
+    /// is the form the shaders take transformations in
+    pub fn transformation(&self) -> [[f32; 4]; 4] {
+        Matrix4::from(self.rotation()).into()
+    }
+}
+
+/// Which of the two segments made by NodeBuffer::divide the children of the divided node end up on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitChildren {
+    /// Every child moves to the tip of the upper segment, so nothing moves in the world
+    Upper,
+    /// The first child, which continues the axis, moves to the upper segment, and the laterals
+    /// stay on the lower segment, growing from the split point
+    LateralsLower,
+}
+
+/// Where a node is in world space, see NodeBuffer::update_frames
+#[derive(Clone, Copy, Debug)]
+pub struct WorldFrame {
+    pub base: Vector3<f32>, // The point the node grows from, which is the tip of its parent
+    pub tip: Vector3<f32>,
+    pub rotation: Quaternion<f32>, // Rotation of the node's frame, whose y axis runs along the node
+}
+
+impl Default for WorldFrame {
+    fn default() -> WorldFrame {
+        WorldFrame::new()
+    }
+}
+
+impl WorldFrame {
+    pub fn new() -> WorldFrame {
+        WorldFrame {
+            base: Vector3::new(0.0, 0.0, 0.0),
+            tip: Vector3::new(0.0, 0.0, 0.0),
+            rotation: Quaternion::one(),
+        }
+    }
+}
+
+/// The nodes of a NodeBuffer, stored as one column per field instead of one struct per node.
+/// Passes over the whole buffer only pull the columns they use into the cache, instead of the
+/// whole node. Columns are indexed by node index
+#[derive(Clone)]
+pub struct NodeColumns {
+    // Topology
+    pub firstChildIndex: Vec<u32>,
+    pub nextSiblingIndex: Vec<u32>,
+    pub parentIndex: Vec<u32>,
+    // Growth state
+    pub status: Vec<u32>,
+    pub age: Vec<u32>,
+    pub archetypeId: Vec<u32>,
+    pub plantId: Vec<u32>,
+    pub plantGeneration: Vec<u32>,
+    pub visible: Vec<u32>,
+    pub length: Vec<f32>,
+    pub radius: Vec<f32>,
+    pub volume: Vec<f32>,
+    // Transform, see Node::rotation
+    pub orientation: Vec<Quaternion<f32>>,
+    pub roll: Vec<f32>,
+    // World space cache, see NodeBuffer::update_frames
+    pub frame: Vec<WorldFrame>,
+    pub moved: Vec<bool>, // Set when the frame of the node and its descendants must be recomputed
+}
+
+impl NodeColumns {
+    /// Returns a handle to the plant the node at index belongs to
+    pub fn plant_handle(&self, index: usize) -> PlantHandle {
+        Handle::new(self.plantId[index], self.plantGeneration[index])
+    }
+
+    /// Returns the rotation of the node at index relative to its parent, see Node::rotation
+    pub fn rotation(&self, index: usize) -> Quaternion<f32> {
+        renormalize(self.orientation[index] * Quaternion::from_angle_y(Rad(self.roll[index])))
+    }
+
+    /// Marks the world frame of the node at index, and those of its descendants, as out of date.
+    /// Must be called whenever the length, orientation or parent of the node is changed through
+    /// the columns
+    pub fn invalidate(&mut self, index: usize) {
+        self.moved[index] = true;
+    }
+
+    /// Returns the last child of the node at index, or INVALID_INDEX if it has no children
+    pub fn last_child(&self, index: usize) -> u32 {
+        let mut child = self.firstChildIndex[index];
+        while child != INVALID_INDEX && self.nextSiblingIndex[child as usize] != INVALID_INDEX {
+            child = self.nextSiblingIndex[child as usize];
+        }
+        child
+    }
+
+    /// Grows the length and radius of the node at index logistically towards their maxima, and
+    /// recomputes its volume. The node is only invalidated if its shape changed, so that nodes
+    /// that have stopped growing keep their frames
+    pub fn grow(
+        &mut self,
+        index: usize,
+        max_length: f32,
+        length_scale: f32,
+        max_radius: f32,
+        radius_scale: f32,
+    ) {
+        let (length, radius) = (self.length[index], self.radius[index]);
+        self.reshape(
+            index,
+            length + logisticDelta(length, max_length, length_scale),
+            radius + logisticDelta(radius, max_radius, radius_scale),
+        );
+    }
+
+    /// Sets the length and radius of the node at index. If either of them changes, the node is
+    /// invalidated and its volume is recomputed
+    pub fn reshape(&mut self, index: usize, length: f32, radius: f32) {
+        if self.length[index] != length || self.radius[index] != radius {
+            self.length[index] = length;
+            self.radius[index] = radius;
+            self.invalidate(index);
+            self.update_volume(index);
+        }
+    }
+
+    /// Recomputes the volume of the node at index from its length and radius
+    pub fn update_volume(&mut self, index: usize) {
+        self.volume[index] = PI * self.radius[index] * self.radius[index] * self.length[index];
+    }
+}
+
+impl Storage for NodeColumns {
+    type Entry = Node;
+
+    fn with_len(len: usize) -> NodeColumns {
+        let mut columns = NodeColumns {
+            firstChildIndex: Vec::new(),
+            nextSiblingIndex: Vec::new(),
+            parentIndex: Vec::new(),
+            status: Vec::new(),
+            age: Vec::new(),
+            archetypeId: Vec::new(),
+            plantId: Vec::new(),
+            plantGeneration: Vec::new(),
+            visible: Vec::new(),
+            length: Vec::new(),
+            radius: Vec::new(),
+            volume: Vec::new(),
+            orientation: Vec::new(),
+            roll: Vec::new(),
+            frame: Vec::new(),
+            moved: Vec::new(),
+        };
+        columns.resize(len);
+        columns
+    }
+
+    fn len(&self) -> usize {
+        self.status.len()
+    }
+
+    fn resize(&mut self, len: usize) {
+        self.firstChildIndex.resize(len, INVALID_INDEX);
+        self.nextSiblingIndex.resize(len, INVALID_INDEX);
+        self.parentIndex.resize(len, INVALID_INDEX);
+        self.status.resize(len, STATUS_GARBAGE);
+        self.age.resize(len, 0);
+        self.archetypeId.resize(len, INVALID_ARCHETYPE_INDEX);
+        self.plantId.resize(len, INVALID_INDEX);
+        self.plantGeneration.resize(len, 0);
+        self.visible.resize(len, 0);
+        self.length.resize(len, 0.0);
+        self.radius.resize(len, 0.0);
+        self.volume.resize(len, 0.0);
+        self.orientation.resize(len, Quaternion::one());
+        self.roll.resize(len, 0.0);
+        self.frame.resize(len, WorldFrame::new());
+        self.moved.resize(len, true);
+    }
+
+    fn get(&self, index: usize) -> Node {
+        Node {
+            firstChildIndex: self.firstChildIndex[index],
+            nextSiblingIndex: self.nextSiblingIndex[index],
+            parentIndex: self.parentIndex[index],
+            age: self.age[index],
+            archetypeId: self.archetypeId[index],
+            plantId: self.plantId[index],
+            plantGeneration: self.plantGeneration[index],
+            status: self.status[index],
+            visible: self.visible[index],
+            length: self.length[index],
+            radius: self.radius[index],
+            volume: self.volume[index],
+            orientation: self.orientation[index].into(),
+            roll: self.roll[index],
+        }
+    }
+
+    fn set(&mut self, index: usize, node: Node) {
+        let orientation = renormalize(Quaternion::from(node.orientation));
+        // Only changes that move the node invalidate its frame
+        self.moved[index] |= self.status[index] == STATUS_GARBAGE
+            || self.parentIndex[index] != node.parentIndex
+            || self.plantId[index] != node.plantId
+            || self.length[index] != node.length
+            || self.orientation[index] != orientation
+            || self.roll[index] != node.roll;
+        self.firstChildIndex[index] = node.firstChildIndex;
+        self.nextSiblingIndex[index] = node.nextSiblingIndex;
+        self.parentIndex[index] = node.parentIndex;
+        self.age[index] = node.age;
+        self.archetypeId[index] = node.archetypeId;
+        self.plantId[index] = node.plantId;
+        self.plantGeneration[index] = node.plantGeneration;
+        self.status[index] = node.status;
+        self.visible[index] = node.visible;
+        self.length[index] = node.length;
+        self.radius[index] = node.radius;
+        self.volume[index] = node.volume;
+        self.orientation[index] = orientation;
+        self.roll[index] = node.roll;
+    }
+
+    fn is_garbage(&self, index: usize) -> bool {
+        self.status[index] == STATUS_GARBAGE
+    }
+
+    fn mark_garbage(&mut self, index: usize) {
+        self.status[index] = STATUS_GARBAGE;
+    }
+}
+
+impl Slot for Node {
+    const BUFFER_FULL: Error = Error::NodeBufferFull;
+
+    fn garbage() -> Node {
+        Node::new()
+    }
+
+    fn is_garbage(&self) -> bool {
+        self.status == STATUS_GARBAGE
+    }
+
+    fn mark_garbage(&mut self) {
+        self.status = STATUS_GARBAGE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use cgmath::InnerSpace;
+
+    #[test]
+    fn lateral_bud_leaves_the_axis_at_the_insertion_angle() {
+        let mut archetype = Archetype::new();
+        archetype.insertionAngle = 0.6;
+        let (_, plant) = living_plant();
+        let axis = Vector3::unit_y();
+        let directions: Vec<Vector3<f32>> = [0.0, PI]
+            .iter()
+            .map(|&azimuth| {
+                let bud = lateral_bud(&archetype, plant, azimuth);
+                bud.rotation().rotate_vector(axis)
+            })
+            .collect();
+        for direction in &directions {
+            assert!((direction.angle(axis).0 - 0.6).abs() < 1.0e-5);
+        }
+        // Buds half a turn apart lean away from each other
+        let across = |direction: Vector3<f32>| Vector3::new(direction.x, 0.0, direction.z);
+        assert!((across(directions[0]) + across(directions[1])).magnitude() < 1.0e-5);
+    }
+
+    #[test]
+    fn divided_bud_continues_as_first_child() {
+        let archetype_table = ArchetypeTable::new();
+        let archetype = archetype_table.get(GROWING_BUD_ARCHETYPE_INDEX).unwrap();
+        let (_, plant) = living_plant();
+        let mut node_buffer = NodeBuffer::new(4);
+        let bud = organ(&mut node_buffer, plant, GROWING_BUD_ARCHETYPE_INDEX, 0.0);
+        let lateral = organ(&mut node_buffer, plant, GROWING_BUD_ARCHETYPE_INDEX, 1.0);
+        node_buffer.add_child(bud, lateral);
+
+        node_buffer.divide_bud(bud, archetype).unwrap();
+        let children: Vec<u32> = node_buffer.children(bud).collect();
+        let first = children[0];
+        assert_eq!(node_buffer.get(bud).archetypeId, STEM_ARCHETYPE_INDEX);
+        assert_eq!(
+            node_buffer.get(first).archetypeId,
+            GROWING_BUD_ARCHETYPE_INDEX
+        );
+        assert_ne!(first, lateral);
+        assert!(children.contains(&lateral));
+        assert!(node_buffer.validate().is_empty());
+    }
+
+    #[test]
+    fn divide_moves_children_to_upper_segment() {
+        let (plant_buffer, plant) = living_plant();
+        let (mut node_buffer, [lower, upper, leaf, bud]) = shoot(plant);
+        node_buffer.update_frames(&plant_buffer);
+        let tips: Vec<_> = [lower, upper, leaf, bud]
+            .iter()
+            .map(|&index| node_buffer.frame(index).tip)
+            .collect();
+        let volume = node_buffer.get(lower).volume;
+
+        let (a, b) = node_buffer
+            .divide(0.25, lower, SplitChildren::Upper)
+            .unwrap();
+        assert_eq!(a.index, lower);
+        assert_eq!(node_buffer.children(lower).collect::<Vec<_>>(), [b.index]);
+        assert_eq!(
+            node_buffer.children(b.index).collect::<Vec<_>>(),
+            [upper, leaf, bud]
+        );
+        assert_eq!(node_buffer.get(lower).length, 0.25);
+        assert_eq!(node_buffer.get(b.index).length, 0.75);
+        let split = node_buffer.get(lower).volume + node_buffer.get(b.index).volume;
+        assert!((split - volume).abs() < 1.0e-6);
+        assert!(node_buffer.validate().is_empty());
+
+        // Nothing moves in the world
+        node_buffer.update_frames(&plant_buffer);
+        assert!((node_buffer.frame(b.index).tip - tips[0]).magnitude() < 1.0e-5);
+        for (&index, &tip) in [upper, leaf, bud].iter().zip(&tips[1..]) {
+            assert!((node_buffer.frame(index).tip - tip).magnitude() < 1.0e-5);
+        }
+    }
+
+    #[test]
+    fn divide_can_keep_laterals_on_lower_segment() {
+        let (plant_buffer, plant) = living_plant();
+        let (mut node_buffer, [lower, upper, leaf, bud]) = shoot(plant);
+        let (_, b) = node_buffer
+            .divide(0.5, lower, SplitChildren::LateralsLower)
+            .unwrap();
+        assert_eq!(
+            node_buffer.children(lower).collect::<Vec<_>>(),
+            [b.index, leaf, bud]
+        );
+        assert_eq!(node_buffer.children(b.index).collect::<Vec<_>>(), [upper]);
+        assert!(node_buffer.validate().is_empty());
+
+        // The laterals grow from the split point
+        node_buffer.update_frames(&plant_buffer);
+        let split = node_buffer.frame(lower).tip;
+        assert!((node_buffer.frame(leaf).base - split).magnitude() < 1.0e-5);
+        assert!((node_buffer.frame(b.index).base - split).magnitude() < 1.0e-5);
+    }
+
+    #[test]
+    fn divide_rejects_invalid_fractions() {
+        let (_, plant) = living_plant();
+        let (mut node_buffer, [lower, ..]) = shoot(plant);
+        for fraction in [0.0, 1.0, -0.5, f32::NAN] {
+            assert_eq!(
+                node_buffer.divide(fraction, lower, SplitChildren::Upper),
+                Err(Error::InvalidFraction)
+            );
         }
+        assert_eq!(node_buffer.current_size(), 4);
+        assert_eq!(node_buffer.get(lower).length, 1.0);
+    }
+
+    #[test]
+    fn compact_packs_trees_in_depth_first_order() {
+        let (_, plant) = living_plant();
+        let (mut node_buffer, [lower, upper, leaf, bud]) = shoot(plant);
+        // Leave holes between the nodes of a second tree
+        let junk = organ(&mut node_buffer, plant, LEAF_ARCHETYPE_INDEX, 0.0);
+        let root = organ(&mut node_buffer, plant, STEM_ARCHETYPE_INDEX, 0.0);
+        let more_junk = organ(&mut node_buffer, plant, LEAF_ARCHETYPE_INDEX, 0.0);
+        let child = organ(&mut node_buffer, plant, LEAF_ARCHETYPE_INDEX, 0.0);
+        node_buffer.add_child(root, child);
+        node_buffer.free(junk);
+        node_buffer.free(more_junk);
+        // Takes the slot of the second piece of junk
+        let tip = organ(&mut node_buffer, plant, STEM_ARCHETYPE_INDEX, 0.0);
+        node_buffer.add_child(upper, tip);
+
+        let remap = node_buffer.compact();
+        assert_eq!(remap.len(), node_buffer.size() as usize);
+        assert_eq!(remap[junk as usize], INVALID_INDEX);
+        let live: Vec<u32> = node_buffer.live_indices().collect();
+        assert_eq!(live, (0..7).collect::<Vec<_>>());
+
+        let moved = |index: u32| remap[index as usize];
+        let trees: Vec<Vec<u32>> = node_buffer
+            .roots()
+            .map(|root| node_buffer.depth_first(root).collect())
+            .collect();
+        assert!(trees.contains(&vec![
+            moved(lower),
+            moved(upper),
+            moved(tip),
+            moved(leaf),
+            moved(bud)
+        ]));
+        assert!(trees.contains(&vec![moved(root), moved(child)]));
+        // Every tree takes up a run of indices
+        for tree in trees {
+            assert!(tree.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        }
+        assert!(node_buffer.validate().is_empty());
+    }
+
+    #[test]
+    fn free_subtree_unlinks_it_from_its_parent() {
+        let (_, plant) = living_plant();
+        let (mut node_buffer, [lower, upper, leaf, bud]) = shoot(plant);
+        let tip = organ(&mut node_buffer, plant, STEM_ARCHETYPE_INDEX, 0.0);
+        node_buffer.add_child(upper, tip);
+
+        node_buffer.free_subtree(upper).unwrap();
+        assert_eq!(node_buffer.children(lower).collect::<Vec<_>>(), [leaf, bud]);
+        assert!(node_buffer.is_garbage(upper));
+        assert!(node_buffer.is_garbage(tip));
+        assert_eq!(node_buffer.current_size(), 3);
+        assert!(node_buffer.validate().is_empty());
+
+        assert_eq!(
+            node_buffer.free_subtree(upper),
+            Err(Error::GarbageNode(upper))
+        );
+        let beyond = node_buffer.size();
+        assert_eq!(
+            node_buffer.free_subtree(beyond),
+            Err(Error::InvalidIndex(beyond))
+        );
+    }
+
+    #[test]
+    fn prune_keeps_the_cut_end() {
+        let (_, plant) = living_plant();
+        let (mut node_buffer, [lower, ..]) = shoot(plant);
+        node_buffer.prune(lower).unwrap();
+        assert_eq!(node_buffer.children(lower).count(), 0);
+        assert_eq!(node_buffer.live_indices().collect::<Vec<_>>(), [lower]);
+        assert!(node_buffer.validate().is_empty());
+    }
+
+    #[test]
+    fn removed_plant_leaves_nodes_of_other_plants_as_roots() {
+        let (mut plant_buffer, [first, second]) = living_plants([[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]]);
+        let (mut node_buffer, [_, upper, ..]) = shoot(first);
+        let grafted = organ(&mut node_buffer, second, STEM_ARCHETYPE_INDEX, 0.0);
+        node_buffer.add_child(upper, grafted);
+        let other = stem(&mut node_buffer, second, 2);
+
+        node_buffer.remove_plant(&mut plant_buffer, first).unwrap();
+        let mut roots: Vec<u32> = node_buffer.roots().collect();
+        roots.sort();
+        let mut expected = [grafted, other];
+        expected.sort();
+        assert_eq!(roots, expected);
+        assert_eq!(node_buffer.current_size(), 3);
+        assert_eq!(plant_buffer.resolve(first), None);
+        assert!(plant_buffer.get_handle(second).is_some());
+        assert!(node_buffer.validate().is_empty());
+
+        assert_eq!(
+            node_buffer.remove_plant(&mut plant_buffer, first),
+            Err(Error::StaleHandle)
+        );
+    }
+
+    #[test]
+    fn buffer_grows_keeping_existing_nodes() {
+        let (plant_buffer, plant) = living_plant();
+        let mut node_buffer = NodeBuffer::new(1);
+        let root = stem(&mut node_buffer, plant, 5);
+        assert!(node_buffer.size() >= 5);
+        assert_eq!(node_buffer.current_size(), 5);
+        assert!(node_buffer.validate().is_empty());
+        node_buffer.update_frames(&plant_buffer);
+        let top = node_buffer.depth_first(root).last().unwrap();
+        assert!((node_buffer.frame(top).tip - Vector3::new(0.0, 5.0, 0.0)).magnitude() < 1.0e-5);
+
+        node_buffer.prune(root).unwrap();
+        assert_eq!(node_buffer.current_size(), 1);
+        assert_eq!(node_buffer.high_water_mark(), 5);
+    }
+
+    #[test]
+    fn buffer_stops_growing_at_its_max_size() {
+        let (_, plant) = living_plant();
+        let mut node_buffer = NodeBuffer::with_max_size(1, 2);
+        stem(&mut node_buffer, plant, 2);
+        assert_eq!(node_buffer.try_alloc(), Err(Error::NodeBufferFull));
+        assert_eq!(node_buffer.size(), 2);
+    }
+
+    #[test]
+    fn node_handle_goes_stale_when_its_slot_is_reused() {
+        let (_, plant) = living_plant();
+        let (mut node_buffer, [lower, _, leaf, _]) = shoot(plant);
+        let handle = node_buffer.handle(leaf);
+        node_buffer.free_subtree(leaf).unwrap();
+        let reused = organ(&mut node_buffer, plant, LEAF_ARCHETYPE_INDEX, 0.0);
+        node_buffer.add_child(lower, reused);
+        assert_eq!(reused, leaf);
+        assert_eq!(node_buffer.resolve(handle), None);
+        assert!(node_buffer.get_handle(handle).is_none());
+        assert!(node_buffer.get_handle(node_buffer.handle(reused)).is_some());
+    }
+
+    #[test]
+    fn node_of_a_freed_plant_does_not_belong_to_its_successor() {
+        let (mut plant_buffer, plant) = living_plant();
+        let mut node_buffer = NodeBuffer::new(1);
+        let root = stem(&mut node_buffer, plant, 1);
+        plant_buffer.free(plant.index);
+        let mut seed = Plant::new();
+        seed.status = STATUS_DORMANT;
+        let successor = plant_buffer.alloc_insert(seed);
+        assert_eq!(successor.index, plant.index);
+        let owner = node_buffer.get(root).plant_handle();
+        assert_eq!(owner, plant);
+        assert!(plant_buffer.get_handle(owner).is_none());
+        assert_eq!(
+            node_buffer.remove_plant(&mut plant_buffer, owner),
+            Err(Error::StaleHandle)
+        );
+    }
+
+    #[test]
+    fn columns_read_back_the_node_written_to_them() {
+        let (_, plant) = living_plant();
+        let mut node = Node::new();
+        node.status = STATUS_ALIVE;
+        node.age = 7;
+        node.archetypeId = LEAF_ARCHETYPE_INDEX;
+        node.set_plant(plant);
+        node.visible = 1;
+        node.length = 0.5;
+        node.radius = 0.25;
+        node.volume = cylinder_volume(node);
+        node.set_orientation(Quaternion::from_angle_x(Rad(0.3)));
+        node.roll = 0.2;
+        let mut node_buffer = NodeBuffer::new(2);
+        let parent = node_buffer.alloc();
+        let index = node_buffer.alloc();
+        node.parentIndex = parent;
+        node_buffer.set(index, node);
+
+        let read = node_buffer.get(index);
+        assert_eq!(format!("{:?}", read), format!("{:?}", node));
+        let nodes = node_buffer.columns();
+        assert_eq!(nodes.length[index as usize], 0.5);
+        assert_eq!(nodes.parentIndex[index as usize], parent);
+        assert_eq!(nodes.plant_handle(index as usize), plant);
+    }
+
+    #[test]
+    fn composed_rotations_stay_unit_length() {
+        let mut node = Node::new();
+        let step = Quaternion::from_axis_angle(Vector3::new(1.0, 2.0, 3.0).normalize(), Rad(0.01));
+        for _ in 0..10000 {
+            node.rotate(step);
+        }
+        let orientation = Quaternion::from(node.orientation);
+        assert!((orientation.magnitude() - 1.0).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn roll_turns_the_node_about_its_own_axis() {
+        let mut node = Node::new();
+        node.set_orientation(Quaternion::from_angle_z(Rad(0.5)));
+        let axis = node.rotation().rotate_vector(Vector3::unit_y());
+        let side = node.rotation().rotate_vector(Vector3::unit_x());
+        node.roll = PI / 2.0;
+        assert!((node.rotation().rotate_vector(Vector3::unit_y()) - axis).magnitude() < 1.0e-6);
+        let rolled = node.rotation().rotate_vector(Vector3::unit_x());
+        assert!(rolled.dot(side).abs() < 1.0e-6);
+
+        // The matrix handed to the shaders turns vectors the same way
+        let matrix = Matrix4::from(node.transformation());
+        let turned = matrix * Vector4::new(1.0, 0.0, 0.0, 0.0);
+        assert!((turned.truncate() - rolled).magnitude() < 1.0e-6);
+    }
+
+    #[test]
+    fn frames_chain_from_the_plant_location() {
+        let (plant_buffer, [plant]) = living_plants([[2.0, 0.0, 1.0]]);
+        let (mut node_buffer, [lower, upper, leaf, _]) = shoot(plant);
+        node_buffer.update_frames(&plant_buffer);
+        let base = Vector3::new(2.0, 0.0, 1.0);
+        let lower_frame = node_buffer.frame(lower);
+        assert!((lower_frame.base - base).magnitude() < 1.0e-6);
+        assert!(((lower_frame.tip - base).magnitude() - 1.0).abs() < 1.0e-6);
+        for child in [upper, leaf] {
+            let frame = node_buffer.frame(child);
+            assert!((frame.base - lower_frame.tip).magnitude() < 1.0e-6);
+            let rotation = lower_frame.rotation * node_buffer.columns().rotation(child as usize);
+            let tip = frame.base + rotation.rotate_vector(Vector3::unit_y());
+            assert!((frame.tip - tip).magnitude() < 1.0e-5);
+        }
+    }
+
+    #[test]
+    fn only_moved_subtrees_are_recomputed() {
+        let (plant_buffer, plant) = living_plant();
+        let (mut node_buffer, [lower, upper, leaf, bud]) = shoot(plant);
+        let tip = organ(&mut node_buffer, plant, STEM_ARCHETYPE_INDEX, 0.0);
+        node_buffer.add_child(upper, tip);
+        node_buffer.update_frames(&plant_buffer);
+        let nodes = node_buffer.columns();
+        assert!(node_buffer
+            .live_indices()
+            .all(|index| !nodes.moved[index as usize]));
+
+        // Stale frames that are left alone show which nodes were recomputed
+        let stale = WorldFrame::new();
+        for index in [lower, upper, leaf, bud, tip] {
+            node_buffer.columns_mut().frame[index as usize] = stale;
+        }
+        node_buffer.columns_mut().reshape(upper as usize, 2.0, 0.1);
+        node_buffer.update_frames(&plant_buffer);
+        for index in [lower, leaf, bud] {
+            assert_eq!(node_buffer.frame(index).tip, stale.tip);
+        }
+        assert_ne!(node_buffer.frame(upper).tip, stale.tip);
+        let frame = node_buffer.frame(tip);
+        assert!((frame.base - node_buffer.frame(upper).tip).magnitude() < 1.0e-6);
+
+        // Reshaping a node to the shape it already has doesn't move it
+        node_buffer.columns_mut().reshape(upper as usize, 2.0, 0.1);
+        assert!(!node_buffer.columns().moved[upper as usize]);
+    }
+
+    #[test]
+    fn roots_without_a_plant_keep_their_frames() {
+        let (plant_buffer, plant) = living_plant();
+        let mut node_buffer = NodeBuffer::new(1);
+        let root = stem(&mut node_buffer, plant, 1);
+        let mut node = node_buffer.get(root);
+        node.plantId = INVALID_INDEX;
+        node_buffer.set(root, node);
+        node_buffer.update_frames(&plant_buffer);
+        assert!(node_buffer.columns().moved[root as usize]);
+        assert_eq!(node_buffer.frame(root).tip, WorldFrame::new().tip);
     }
 }
@@ -6,7 +6,10 @@ use cgmath::{Deg, InnerSpace, Matrix4, Rad, Transform, Vector3, Vector4};
 use std::convert::TryInto;
 
 use super::archetype::*;
+use super::error::*;
+use super::slot::*;
 use super::vertex::mVertex as Vertex;
+use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 
 pub const INVALID_INDEX: u32 = std::u32::MAX;
@@ -15,103 +18,66 @@ pub const STATUS_GARBAGE: u32 = 0; //Default For Plant, signifies that the plant
 pub const STATUS_DEAD: u32 = 1; //Plant was once alive, but not anymre. It is susceptible to rot
 pub const STATUS_ALIVE: u32 = 2; //Plant is currently alive, and could become dead
 pub const STATUS_NEVER_ALIVE: u32 = 3; //Plant is not alive, and cannot die
+pub const STATUS_DORMANT: u32 = 4; //Plant is a seed in the soil, it has no nodes yet
 
+/// Refers to a plant in a PlantBuffer. The handle goes stale once the plant is freed, even if
+/// its slot is reused by another plant
+pub type PlantHandle = Handle<Plant>;
+
+/// Holds every plant. Allocation is done by the underlying SlotBuffer, which the PlantBuffer
+/// dereferences to
 #[derive(Clone)]
 pub struct PlantBuffer {
-    plant_list: Vec<Plant>,
-    free_stack: Vec<u32>,
-    free_ptr: u32,
-    max_size: u32,
+    plant_list: SlotBuffer<Vec<Plant>>,
 }
 
-impl PlantBuffer {
-    pub fn new(size: u32) -> PlantBuffer {
-        if size == 0 || size == INVALID_INDEX {
-            panic!("invalid size for plant buffer")
-        }
-        PlantBuffer {
-            plant_list: vec![Plant::new(); size as usize], // Create list with default plants
-            free_stack: (0..size).collect(), // Create list of all free plant locations
-            free_ptr: size,                  // The current pointer to the active stack
-            max_size: size,                  // The maximum size to which the stack may grow
-        }
-    }
+impl Deref for PlantBuffer {
+    type Target = SlotBuffer<Vec<Plant>>;
 
-    pub fn get(&self, index: u32) -> Plant {
-        self.plant_list[index as usize].clone()
+    fn deref(&self) -> &SlotBuffer<Vec<Plant>> {
+        &self.plant_list
     }
+}
 
-    pub fn set(&mut self, index: u32, plant: Plant) {
-        self.plant_list[index as usize] = plant.clone();
+impl DerefMut for PlantBuffer {
+    fn deref_mut(&mut self) -> &mut SlotBuffer<Vec<Plant>> {
+        &mut self.plant_list
     }
+}
 
-    /// Returns the index of a free spot in the array (user needs to mark the spot as not garbage)
-    pub fn alloc(&mut self) -> u32 {
-        if self.free_ptr == 0 {
-            panic!("No Memory Left In PlantBuffer");
-        } else {
-            self.free_ptr = self.free_ptr - 1;
-            self.free_stack[self.free_ptr as usize]
+impl PlantBuffer {
+    /// Creates a buffer with room for size plants, which grows whenever it runs out of room
+    pub fn new(size: u32) -> PlantBuffer {
+        PlantBuffer {
+            plant_list: SlotBuffer::new(size),
         }
     }
 
-    pub fn alloc_insert(&mut self, plant: Plant) {
-        let index = self.alloc();
-        self.set(index, plant.clone());
-    }
-
-    /// Marks an index in the array as free to use, marks any plant as garbage
-    pub fn free(&mut self, index: u32) {
-        self.plant_list[index as usize].status = STATUS_GARBAGE;
-        if self.free_ptr == self.max_size {
-            panic!("Free Stack Full (This should not happen)");
-        } else {
-            self.free_stack[self.free_ptr as usize] = index;
-            self.free_ptr = self.free_ptr + 1;
+    /// Creates a buffer with room for size plants, which grows whenever it runs out of room, but
+    /// never beyond max_size plants
+    pub fn with_max_size(size: u32, max_size: u32) -> PlantBuffer {
+        PlantBuffer {
+            plant_list: SlotBuffer::with_max_size(size, max_size),
         }
     }
 
-    /// Rreturns maximum size that the plant list could grow to.
-    pub fn size(&self) -> u32 {
-        self.max_size
-    }
-
-    /// Returns the current size that the plant buffer is at
-    pub fn current_size(&self) -> u32 {
-        self.max_size - self.free_ptr
+    /// Ages all living plants and dormant seeds by one tick
+    pub fn update_all(&mut self) {
+        for (_, plant) in self.plant_list.iter_mut() {
+            if plant.status == STATUS_ALIVE || plant.status == STATUS_DORMANT {
+                plant.age += 1;
+            }
+        }
     }
 }
 
-fn tomat(mat: [[f32; 4]; 4]) -> Matrix4<f32> {
-    Matrix4::from_cols(
-        Vector4::from(mat[0]),
-        Vector4::from(mat[1]),
-        Vector4::from(mat[2]),
-        Vector4::from(mat[3]),
-    )
-}
-
-fn tov(v3: [f32; 3]) -> Vector3<f32> {
-    Vector3::new(v3[0], v3[1], v3[2])
-}
-
-fn to3(v: Vector3<f32>) -> [f32; 3] {
-    [v.x, v.y, v.z]
-}
-
-fn scale3(a: [f32; 3], scalar: f32) -> [f32; 3] {
-    [a[0] * scalar, a[1] * scalar, a[2] * scalar]
-}
-
-fn add3(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
-    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
-}
-
 #[derive(Clone, Copy, Debug)]
 pub struct Plant {
     pub status: u32,
     pub age: u32,
     pub location: [f32; 3],
+    pub carbon: f32, // Carbohydrate reserves available for growth and reproduction
+    pub waterStress: f32, // Ranges from 0 (well watered) to 1 (dies of drought)
 }
 
 impl Plant {
@@ -121,6 +87,65 @@ impl Plant {
             status: STATUS_GARBAGE,
             age: 0,
             location: [0.0, 0.0, 0.0],
+            carbon: 0.0,
+            waterStress: 0.0,
         }
     }
 }
+
+impl Slot for Plant {
+    const BUFFER_FULL: Error = Error::PlantBufferFull;
+
+    fn garbage() -> Plant {
+        Plant::new()
+    }
+
+    fn is_garbage(&self) -> bool {
+        self.status == STATUS_GARBAGE
+    }
+
+    fn mark_garbage(&mut self) {
+        self.status = STATUS_GARBAGE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plant(status: u32) -> Plant {
+        let mut plant = Plant::new();
+        plant.status = status;
+        plant
+    }
+
+    #[test]
+    fn iterates_over_live_plants_only() {
+        let mut plant_buffer = PlantBuffer::new(4);
+        let alive = plant_buffer.alloc_insert(plant(STATUS_ALIVE));
+        let freed = plant_buffer.alloc_insert(plant(STATUS_ALIVE));
+        let dormant = plant_buffer.alloc_insert(plant(STATUS_DORMANT));
+        plant_buffer.free(freed.index);
+
+        let mut live: Vec<u32> = plant_buffer.iter().map(|(index, _)| index).collect();
+        live.sort();
+        let mut expected = [alive.index, dormant.index];
+        expected.sort();
+        assert_eq!(live, expected);
+        assert_eq!(plant_buffer.live_indices().count(), 2);
+        assert_eq!(plant_buffer.current_size(), 2);
+    }
+
+    #[test]
+    fn update_ages_living_plants_and_seeds() {
+        let mut plant_buffer = PlantBuffer::new(3);
+        let alive = plant_buffer.alloc_insert(plant(STATUS_ALIVE));
+        let dormant = plant_buffer.alloc_insert(plant(STATUS_DORMANT));
+        let dead = plant_buffer.alloc_insert(plant(STATUS_DEAD));
+        plant_buffer.update_all();
+        plant_buffer[alive.index].age += 1;
+        assert_eq!(plant_buffer[alive.index].age, 2);
+        assert_eq!(plant_buffer[dormant.index].age, 1);
+        assert_eq!(plant_buffer[dead.index].age, 0);
+    }
+}
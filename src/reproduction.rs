@@ -0,0 +1,201 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+use std::f32::consts::PI;
+
+use super::archetype::*;
+use super::error::*;
+use super::grid::*;
+use super::node::*;
+use super::plant::*;
+
+/// Places a dormant seed at location, carrying carbon to start growing with once it germinates.
+/// Returns the index of the new plant, or an error if the plant buffer is full
+pub fn sow(plant_buffer: &mut PlantBuffer, location: [f32; 3], carbon: f32) -> Result<u32> {
+    let pindex = plant_buffer.try_alloc()?;
+    let mut plant = Plant::new();
+    plant.location = location;
+    plant.status = STATUS_DORMANT;
+    plant.carbon = carbon;
+    plant_buffer.set(pindex, plant);
+    Ok(pindex)
+}
+
+/// Returns a random distance drawn from an exponential dispersal kernel with the given mean
+fn dispersal_kernel(mean: f32) -> f32 {
+    -mean * (1.0 - rand::random::<f32>()).ln()
+}
+
+/// Releases the seeds of every ripe fruit. The number of seeds is limited by the carbon the
+/// parent plant can spare. Each seed is carried a random distance away from its parent, and if it
+/// lands on soil within the grid, it is sown on the soil surface, where it lies dormant until it
+/// germinates. Spent fruits are left dead on the parent plant. If the plant buffer runs out of
+/// room, the remaining seeds of that fruit are lost and an error is returned
+pub fn disperse_seeds(
+    node_buffer: &mut NodeBuffer,
+    plant_buffer: &mut PlantBuffer,
+    grid_buffer: &GridBuffer,
+    archetype_table: &ArchetypeTable,
+) -> Result<()> {
+    let fruit = archetype_table
+        .get(FRUIT_ARCHETYPE_INDEX)
+        .ok_or(Error::UnknownArchetype(FRUIT_ARCHETYPE_INDEX))?;
+
+    for ni in 0..node_buffer.size() {
+        let mut node = node_buffer.get(ni);
+        if node.status != STATUS_ALIVE
+            || node.archetypeId != FRUIT_ARCHETYPE_INDEX
+            || node.age < fruit.maturationAge
+        {
+            continue;
+        }
+
+        node.status = STATUS_DEAD;
+        node_buffer.set(ni, node);
+
+        let mut parent = match plant_buffer.get_handle(node.plant_handle()) {
+            Some(parent) => parent,
+            None => continue,
+        };
+        let affordable = if fruit.seedCost > 0.0 {
+            (parent.carbon.max(0.0) / fruit.seedCost) as u32
+        } else {
+            fruit.seedsPerFruit
+        };
+        let seed_count = fruit.seedsPerFruit.min(affordable);
+        parent.carbon -= seed_count as f32 * fruit.seedCost;
+        plant_buffer.set(node.plantId, parent);
+
+        for _ in 0..seed_count {
+            let distance = dispersal_kernel(fruit.dispersalDistance);
+            let direction = rand::random::<f32>() * 2.0 * PI;
+            let x = parent.location[0] + distance * direction.cos();
+            let z = parent.location[2] + distance * direction.sin();
+            if x < 0.0 || z < 0.0 {
+                continue;
+            }
+            if let Some(surface) = grid_buffer.soil_surface(x as u32, z as u32) {
+                sow(plant_buffer, [x, surface as f32, z], fruit.seedCost)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    /// Returns a grid one layer of soil deep, with a layer of air above it
+    fn soil(size: u32) -> GridBuffer {
+        let mut grid_buffer = GridBuffer::new(size, 2, size);
+        for x in 0..size {
+            for z in 0..size {
+                let mut cell = GridCell::new();
+                cell.typeCode = GRIDCELL_TYPE_SOIL;
+                grid_buffer.set(x, 0, z, cell);
+            }
+        }
+        grid_buffer
+    }
+
+    /// Returns a table whose fruits ripen after ten ticks and drop their seeds right beneath
+    /// their plant
+    fn archetypes() -> ArchetypeTable {
+        let mut archetype_table = ArchetypeTable::new();
+        let mut fruit = archetype_table.get(FRUIT_ARCHETYPE_INDEX).unwrap().clone();
+        fruit.maturationAge = 10;
+        fruit.seedsPerFruit = 3;
+        fruit.seedCost = 1.0;
+        fruit.dispersalDistance = 0.0;
+        archetype_table.set(FRUIT_ARCHETYPE_INDEX, fruit);
+        archetype_table
+    }
+
+    fn fruit(node_buffer: &mut NodeBuffer, plant: PlantHandle, age: u32) -> u32 {
+        let index = organ(node_buffer, plant, FRUIT_ARCHETYPE_INDEX, 0.0);
+        let mut node = node_buffer.get(index);
+        node.age = age;
+        node_buffer.set(index, node);
+        index
+    }
+
+    #[test]
+    fn ripe_fruit_sows_the_seeds_its_plant_can_afford() {
+        let archetype_table = archetypes();
+        let grid_buffer = soil(4);
+        let (mut plant_buffer, [parent]) = living_plants([[2.5, 1.0, 2.5]]);
+        let pindex = plant_buffer.resolve(parent).unwrap();
+        let mut plant = plant_buffer.get(pindex);
+        plant.carbon = 2.5;
+        plant_buffer.set(pindex, plant);
+        let mut node_buffer = NodeBuffer::new(2);
+        let ripe = fruit(&mut node_buffer, parent, 10);
+        let green = fruit(&mut node_buffer, parent, 9);
+
+        disperse_seeds(
+            &mut node_buffer,
+            &mut plant_buffer,
+            &grid_buffer,
+            &archetype_table,
+        )
+        .unwrap();
+        assert_eq!(node_buffer.get(ripe).status, STATUS_DEAD);
+        assert_eq!(node_buffer.get(green).status, STATUS_ALIVE);
+        assert_eq!(plant_buffer.get(pindex).carbon, 0.5);
+        let seeds: Vec<Plant> = plant_buffer
+            .iter()
+            .filter(|&(index, _)| index != pindex)
+            .map(|(_, seed)| seed)
+            .collect();
+        assert_eq!(seeds.len(), 2);
+        for seed in seeds {
+            assert_eq!(seed.status, STATUS_DORMANT);
+            assert_eq!(seed.location, [2.5, 1.0, 2.5]);
+            assert_eq!(seed.carbon, 1.0);
+        }
+    }
+
+    #[test]
+    fn seeds_off_the_grid_are_lost() {
+        let archetype_table = archetypes();
+        let grid_buffer = soil(4);
+        let (mut plant_buffer, [parent]) = living_plants([[6.0, 1.0, 2.0]]);
+        let mut node_buffer = NodeBuffer::new(1);
+        let ripe = fruit(&mut node_buffer, parent, 10);
+
+        disperse_seeds(
+            &mut node_buffer,
+            &mut plant_buffer,
+            &grid_buffer,
+            &archetype_table,
+        )
+        .unwrap();
+        assert_eq!(node_buffer.get(ripe).status, STATUS_DEAD);
+        assert_eq!(plant_buffer.iter().count(), 1);
+    }
+
+    #[test]
+    fn full_plant_buffer_stops_dispersal() {
+        let archetype_table = archetypes();
+        let grid_buffer = soil(4);
+        let mut plant_buffer = PlantBuffer::with_max_size(1, 1);
+        let mut plant = Plant::new();
+        plant.status = STATUS_ALIVE;
+        plant.location = [1.5, 1.0, 1.5];
+        plant.carbon = 3.0;
+        let parent = plant_buffer.alloc_insert(plant);
+        let mut node_buffer = NodeBuffer::new(1);
+        fruit(&mut node_buffer, parent, 10);
+
+        assert_eq!(
+            disperse_seeds(
+                &mut node_buffer,
+                &mut plant_buffer,
+                &grid_buffer,
+                &archetype_table,
+            ),
+            Err(Error::PlantBufferFull)
+        );
+    }
+}
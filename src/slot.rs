@@ -0,0 +1,515 @@
+#![allow(dead_code)]
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+
+use super::error::*;
+
+/// An entry that can be stored in a SlotBuffer
+pub trait Slot: Clone {
+    /// The error returned when a buffer of these entries can't grow any further
+    const BUFFER_FULL: Error;
+
+    /// Returns an entry in its default state, marked as garbage
+    fn garbage() -> Self;
+
+    /// Returns true if the entry is garbage, meaning its slot is free or was just allocated
+    fn is_garbage(&self) -> bool;
+
+    /// Marks the entry as garbage
+    fn mark_garbage(&mut self);
+}
+
+/// Refers to an entry in a SlotBuffer. The handle goes stale once the entry is freed, even if its
+/// slot is reused by another entry
+pub struct Handle<T> {
+    pub index: u32,
+    pub generation: u32,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    pub fn new(index: u32, generation: u32) -> Handle<T> {
+        Handle {
+            index,
+            generation,
+            marker: PhantomData,
+        }
+    }
+}
+
+// These are implemented by hand, since deriving them would require T to implement them as well
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Handle<T> {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Handle<T>) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Handle")
+            .field("index", &self.index)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+/// Where a SlotBuffer keeps its entries. Entries can be stored whole in a Vec, or split into
+/// one column per field, see node::NodeColumns
+pub trait Storage: Clone {
+    type Entry: Slot;
+
+    /// Creates storage for len entries, all of them garbage
+    fn with_len(len: usize) -> Self;
+
+    /// Returns the number of slots
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Grows the storage to len slots, filling the new ones with garbage
+    fn resize(&mut self, len: usize);
+
+    fn get(&self, index: usize) -> Self::Entry;
+
+    fn set(&mut self, index: usize, entry: Self::Entry);
+
+    /// Returns true if the entry at index is garbage. Storage that splits entries into columns
+    /// should only need to read a single column for this
+    fn is_garbage(&self, index: usize) -> bool;
+
+    fn mark_garbage(&mut self, index: usize);
+}
+
+impl<T: Slot> Storage for Vec<T> {
+    type Entry = T;
+
+    fn with_len(len: usize) -> Vec<T> {
+        vec![T::garbage(); len]
+    }
+
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn resize(&mut self, len: usize) {
+        Vec::resize(self, len, T::garbage());
+    }
+
+    fn get(&self, index: usize) -> T {
+        self[index].clone()
+    }
+
+    fn set(&mut self, index: usize, entry: T) {
+        self[index] = entry;
+    }
+
+    fn is_garbage(&self, index: usize) -> bool {
+        self[index].is_garbage()
+    }
+
+    fn mark_garbage(&mut self, index: usize) {
+        self[index].mark_garbage();
+    }
+}
+
+/// A list of entries whose slots are handed out from a free stack. The buffer grows whenever it
+/// runs out of free slots, up to a maximum size, and never moves an entry to another index
+#[derive(Clone)]
+pub struct SlotBuffer<S: Storage> {
+    entries: S,
+    generations: Vec<u32>,
+    allocated: Vec<bool>,
+    free_stack: Vec<u32>,
+    max_size: u32,
+    high_water_mark: u32,
+}
+
+impl<S: Storage> SlotBuffer<S> {
+    /// Creates a buffer with room for size entries, which grows whenever it runs out of room
+    pub fn new(size: u32) -> SlotBuffer<S> {
+        SlotBuffer::with_max_size(size, u32::MAX)
+    }
+
+    /// Creates a buffer with room for size entries, which grows whenever it runs out of room, but
+    /// never beyond max_size entries
+    pub fn with_max_size(size: u32, max_size: u32) -> SlotBuffer<S> {
+        if size == 0 || size == u32::MAX || max_size < size {
+            panic!("invalid size for slot buffer")
+        }
+        SlotBuffer {
+            entries: S::with_len(size as usize), // Create list with default entries
+            generations: vec![0; size as usize], // Incremented every time a slot is freed
+            allocated: vec![false; size as usize], // Whether each slot is off the free stack
+            free_stack: (0..size).collect(),     // Create list of all free slots
+            max_size,                            // The maximum size to which the buffer may grow
+            high_water_mark: 0,                  // The most entries that have been in use at once
+        }
+    }
+
+    /// Returns the storage holding the entries, for code that works on whole columns at once
+    pub fn storage(&self) -> &S {
+        &self.entries
+    }
+
+    /// Returns the storage holding the entries mutably. Entries must not be marked as garbage
+    /// through it, use free instead
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.entries
+    }
+
+    pub fn get(&self, index: u32) -> S::Entry {
+        self.entries.get(index as usize)
+    }
+
+    pub fn set(&mut self, index: u32, entry: S::Entry) {
+        self.entries.set(index as usize, entry);
+    }
+
+    /// Returns the entry at index, or an error if index is outside of the buffer
+    pub fn try_get(&self, index: u32) -> Result<S::Entry> {
+        if index >= self.size() {
+            return Err(Error::InvalidIndex(index));
+        }
+        Ok(self.get(index))
+    }
+
+    /// Sets the entry at index, or returns an error if index is outside of the buffer
+    pub fn try_set(&mut self, index: u32, entry: S::Entry) -> Result<()> {
+        if index >= self.size() {
+            return Err(Error::InvalidIndex(index));
+        }
+        self.set(index, entry);
+        Ok(())
+    }
+
+    /// Returns true if the entry at index is garbage
+    pub fn is_garbage(&self, index: u32) -> bool {
+        self.entries.is_garbage(index as usize)
+    }
+
+    /// Returns a handle to the slot at index, which stays valid until the slot is freed
+    pub fn handle(&self, index: u32) -> Handle<S::Entry> {
+        Handle::new(index, self.generations[index as usize])
+    }
+
+    /// Returns the index handle refers to, or None if the handle is stale
+    pub fn resolve(&self, handle: Handle<S::Entry>) -> Option<u32> {
+        match self.generations.get(handle.index as usize) {
+            Some(&generation) if generation == handle.generation => Some(handle.index),
+            _ => None,
+        }
+    }
+
+    /// Returns the entry handle refers to, or None if the handle is stale or the entry is garbage
+    pub fn get_handle(&self, handle: Handle<S::Entry>) -> Option<S::Entry> {
+        let index = self.resolve(handle)?;
+        if self.is_garbage(index) {
+            None
+        } else {
+            Some(self.get(index))
+        }
+    }
+
+    /// Sets the entry handle refers to, or returns an error if the handle is stale
+    pub fn set_handle(&mut self, handle: Handle<S::Entry>, entry: S::Entry) -> Result<()> {
+        let index = self.resolve(handle).ok_or(Error::StaleHandle)?;
+        self.set(index, entry);
+        Ok(())
+    }
+    /// Returns the index of a free spot in the array (user needs to mark the spot as not garbage)
+    pub fn alloc(&mut self) -> u32 {
+        match self.try_alloc() {
+            Ok(index) => index,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Returns the index of a free spot in the array, growing the buffer if there is none, or an
+    /// error if the buffer can't grow any further
+    pub fn try_alloc(&mut self) -> Result<u32> {
+        self.reserve(1)?;
+        let index = self.free_stack.pop().ok_or(S::Entry::BUFFER_FULL)?;
+        self.allocated[index as usize] = true;
+        self.high_water_mark = self.high_water_mark.max(self.current_size());
+        Ok(index)
+    }
+
+    /// Returns the indices of count free spots in the array, or an error without allocating
+    /// anything if there is no room for count more entries
+    pub fn try_alloc_many(&mut self, count: u32) -> Result<Vec<u32>> {
+        self.reserve(count)?;
+        Ok((0..count).map(|_| self.alloc()).collect())
+    }
+
+    /// Grows the buffer until at least count spots are free, by doubling its size but never
+    /// beyond the maximum size. Indices of existing entries are preserved
+    pub fn reserve(&mut self, count: u32) -> Result<()> {
+        let free = self.free_stack.len() as u32;
+        if free >= count {
+            return Ok(());
+        }
+        let size = self.size();
+        let required = size
+            .checked_add(count - free)
+            .filter(|&required| required <= self.max_size)
+            .ok_or(S::Entry::BUFFER_FULL)?;
+        let new_size = size.saturating_mul(2).max(required).min(self.max_size);
+        self.entries.resize(new_size as usize);
+        self.generations.resize(new_size as usize, 0);
+        self.allocated.resize(new_size as usize, false);
+        self.free_stack.extend(size..new_size);
+        Ok(())
+    }
+
+    /// Allocates a slot for entry, and returns a handle to it
+    pub fn alloc_insert(&mut self, entry: S::Entry) -> Handle<S::Entry> {
+        let index = self.alloc();
+        self.set(index, entry);
+        self.handle(index)
+    }
+
+    /// Marks an index in the array as free to use, marks any entry as garbage
+    pub fn free(&mut self, index: u32) {
+        if let Err(e) = self.try_free(index) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Marks an index in the array as free to use, or returns an error if index is outside of the
+    /// buffer, the slot at index is already free, or the free stack is already full. A slot that
+    /// was allocated but never set can be freed as well
+    pub fn try_free(&mut self, index: u32) -> Result<()> {
+        if index >= self.size() {
+            return Err(Error::InvalidIndex(index));
+        }
+        if !self.allocated[index as usize] {
+            return Err(Error::DoubleFree(index));
+        }
+        if self.free_stack.len() == self.entries.len() {
+            return Err(Error::FreeStackFull);
+        }
+        self.entries.mark_garbage(index as usize);
+        self.allocated[index as usize] = false;
+        self.generations[index as usize] = self.generations[index as usize].wrapping_add(1);
+        self.free_stack.push(index);
+        Ok(())
+    }
+
+    /// Returns the number of slots in the buffer, every index is less than this
+    pub fn size(&self) -> u32 {
+        self.entries.len() as u32
+    }
+
+    /// Returns the maximum size that the buffer could grow to
+    pub fn max_size(&self) -> u32 {
+        self.max_size
+    }
+
+    /// Returns the current size that the buffer is at
+    pub fn current_size(&self) -> u32 {
+        self.size() - self.free_stack.len() as u32
+    }
+
+    /// Returns the largest number of entries that have been in use at once
+    pub fn high_water_mark(&self) -> u32 {
+        self.high_water_mark
+    }
+
+    /// Returns the free stack, the slot on top is the next one to be allocated
+    pub fn free_slots(&self) -> &[u32] {
+        &self.free_stack
+    }
+
+    /// Moves the entries at the indices in order to the front of the buffer, so that the entry at
+    /// order[k] ends up at index k, and frees every slot that is not in order. Returns a table
+    /// mapping every old index to its new one, or to u32::MAX for slots that were freed. All
+    /// existing handles go stale, they can be translated with the table. Indices in order must be
+    /// distinct
+    pub fn reorder(&mut self, order: &[u32]) -> Vec<u32> {
+        let size = self.size();
+        let mut remap = vec![u32::MAX; size as usize];
+        let mut entries = S::with_len(size as usize);
+        for (new_index, &old_index) in order.iter().enumerate() {
+            remap[old_index as usize] = new_index as u32;
+            entries.set(new_index, self.entries.get(old_index as usize));
+        }
+        self.entries = entries;
+        for generation in self.generations.iter_mut() {
+            *generation = generation.wrapping_add(1);
+        }
+        // The lowest free slot is allocated first, so that new entries end up next to the others
+        self.free_stack = (order.len() as u32..size).rev().collect();
+        for (index, allocated) in self.allocated.iter_mut().enumerate() {
+            *allocated = index < order.len();
+        }
+        remap
+    }
+
+    /// Iterates over the indices of all slots that are not garbage
+    pub fn live_indices(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.size()).filter(move |&index| !self.is_garbage(index))
+    }
+
+    /// Iterates over the indices and entries of all slots that are not garbage
+    pub fn iter(&self) -> impl Iterator<Item = (u32, S::Entry)> + '_ {
+        self.live_indices()
+            .map(move |index| (index, self.get(index)))
+    }
+}
+
+impl<T: Slot> SlotBuffer<Vec<T>> {
+    /// Iterates mutably over the indices and entries of all slots that are not garbage
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (u32, &mut T)> {
+        self.entries
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, entry)| !entry.is_garbage())
+            .map(|(index, entry)| (index as u32, entry))
+    }
+}
+
+impl<T: Slot> Index<u32> for SlotBuffer<Vec<T>> {
+    type Output = T;
+
+    fn index(&self, index: u32) -> &T {
+        &self.entries[index as usize]
+    }
+}
+
+impl<T: Slot> IndexMut<u32> for SlotBuffer<Vec<T>> {
+    fn index_mut(&mut self, index: u32) -> &mut T {
+        &mut self.entries[index as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A slot holding a number, which is garbage while it is zero
+    #[derive(Clone, Debug, PartialEq)]
+    struct Number(u32);
+
+    impl Slot for Number {
+        const BUFFER_FULL: Error = Error::PlantBufferFull;
+
+        fn garbage() -> Number {
+            Number(0)
+        }
+
+        fn is_garbage(&self) -> bool {
+            self.0 == 0
+        }
+
+        fn mark_garbage(&mut self) {
+            self.0 = 0;
+        }
+    }
+
+    type Numbers = SlotBuffer<Vec<Number>>;
+
+    #[test]
+    fn grows_when_out_of_slots() {
+        let mut buffer = Numbers::new(2);
+        let mut indices: Vec<u32> = (1..=3)
+            .map(|n| buffer.alloc_insert(Number(n)).index)
+            .collect();
+        assert_eq!(buffer.size(), 4);
+        assert_eq!(buffer.current_size(), 3);
+        assert_eq!(buffer.high_water_mark(), 3);
+        indices.sort();
+        indices.dedup();
+        assert_eq!(indices.len(), 3);
+    }
+
+    #[test]
+    fn never_grows_beyond_max_size() {
+        let mut buffer = Numbers::with_max_size(2, 3);
+        assert_eq!(buffer.try_alloc_many(4), Err(Error::PlantBufferFull));
+        assert_eq!(buffer.current_size(), 0);
+        assert_eq!(buffer.try_alloc_many(3).map(|indices| indices.len()), Ok(3));
+        assert_eq!(buffer.size(), 3);
+        assert_eq!(buffer.try_alloc(), Err(Error::PlantBufferFull));
+        assert_eq!(buffer.reserve(1), Err(Error::PlantBufferFull));
+    }
+
+    #[test]
+    fn reserve_keeps_indices() {
+        let mut buffer = Numbers::new(1);
+        let handle = buffer.alloc_insert(Number(7));
+        buffer.reserve(10).unwrap();
+        assert!(buffer.free_slots().len() >= 10);
+        assert_eq!(buffer.get_handle(handle), Some(Number(7)));
+    }
+
+    #[test]
+    fn double_free_is_rejected() {
+        let mut buffer = Numbers::new(2);
+        let index = buffer.alloc_insert(Number(1)).index;
+        assert_eq!(buffer.try_free(index), Ok(()));
+        assert_eq!(buffer.try_free(index), Err(Error::DoubleFree(index)));
+        assert_eq!(buffer.try_free(2), Err(Error::InvalidIndex(2)));
+        // A slot that was allocated but never set can still be freed, and is then reused
+        let unset = buffer.alloc();
+        assert_eq!(buffer.try_free(unset), Ok(()));
+        assert_eq!(buffer.try_free(unset), Err(Error::DoubleFree(unset)));
+        assert_eq!(buffer.free_slots().len(), 2);
+        assert_eq!(buffer.alloc(), unset);
+    }
+
+    #[test]
+    fn handles_go_stale_when_freed() {
+        let mut buffer = Numbers::new(1);
+        let old = buffer.alloc_insert(Number(1));
+        buffer.free(old.index);
+        let new = buffer.alloc_insert(Number(2));
+        assert_eq!(new.index, old.index);
+        assert_ne!(new.generation, old.generation);
+        assert_eq!(buffer.resolve(old), None);
+        assert_eq!(buffer.get_handle(old), None);
+        assert_eq!(buffer.set_handle(old, Number(3)), Err(Error::StaleHandle));
+        assert_eq!(buffer.get_handle(new), Some(Number(2)));
+    }
+
+    #[test]
+    fn reorder_moves_entries_to_the_front() {
+        let mut buffer = Numbers::new(4);
+        let indices: Vec<u32> = (1..=4)
+            .map(|n| buffer.alloc_insert(Number(n)).index)
+            .collect();
+        let handle = buffer.handle(indices[2]);
+        buffer.free(indices[1]);
+        let remap = buffer.reorder(&[indices[3], indices[2], indices[0]]);
+        assert_eq!(remap[indices[3] as usize], 0);
+        assert_eq!(remap[indices[2] as usize], 1);
+        assert_eq!(remap[indices[0] as usize], 2);
+        assert_eq!(remap[indices[1] as usize], u32::MAX);
+        assert_eq!(buffer.get(0), Number(4));
+        assert_eq!(buffer.get(1), Number(3));
+        assert_eq!(buffer.get(2), Number(1));
+        assert!(buffer.is_garbage(3));
+        assert_eq!(buffer.resolve(handle), None);
+        assert_eq!(buffer.alloc(), 3);
+    }
+}
@@ -0,0 +1,220 @@
+#![allow(dead_code)]
+use std::collections::VecDeque;
+
+use super::node::*;
+use super::plant::*;
+
+/// Visits the children of a node in order, starting with its first child. Created by
+/// NodeBuffer::children
+pub struct Children<'a> {
+    nodes: &'a NodeColumns,
+    current: u32,
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.current == INVALID_INDEX {
+            return None;
+        }
+        let child = self.current;
+        self.current = self.nodes.nextSiblingIndex[child as usize];
+        Some(child)
+    }
+}
+
+/// Visits a subtree in depth first pre-order, parents before their children and children in
+/// order. Created by NodeBuffer::depth_first
+pub struct DepthFirst<'a> {
+    nodes: &'a NodeColumns,
+    root: u32,
+    stack: Vec<u32>,
+}
+
+impl<'a> Iterator for DepthFirst<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let index = self.stack.pop()?;
+        // The next sibling is visited once the node's own subtree is done. Siblings of the root
+        // are not part of the subtree
+        let sibling = self.nodes.nextSiblingIndex[index as usize];
+        if sibling != INVALID_INDEX && index != self.root {
+            self.stack.push(sibling);
+        }
+        let child = self.nodes.firstChildIndex[index as usize];
+        if child != INVALID_INDEX {
+            self.stack.push(child);
+        }
+        Some(index)
+    }
+}
+
+/// Visits a subtree level by level, starting with its root. Created by NodeBuffer::breadth_first
+pub struct BreadthFirst<'a> {
+    nodes: &'a NodeColumns,
+    queue: VecDeque<u32>,
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let index = self.queue.pop_front()?;
+        let mut child = self.nodes.firstChildIndex[index as usize];
+        while child != INVALID_INDEX {
+            self.queue.push_back(child);
+            child = self.nodes.nextSiblingIndex[child as usize];
+        }
+        Some(index)
+    }
+}
+
+/// Visits the parent of a node, then its parent, up to the root of the tree. Created by
+/// NodeBuffer::ancestors
+pub struct Ancestors<'a> {
+    nodes: &'a NodeColumns,
+    current: u32,
+}
+
+impl<'a> Iterator for Ancestors<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let parent = self.nodes.parentIndex[self.current as usize];
+        if parent == INVALID_INDEX {
+            return None;
+        }
+        self.current = parent;
+        Some(parent)
+    }
+}
+
+impl NodeBuffer {
+    /// Iterates over the children of node_index, in the order they were added
+    pub fn children(&self, node_index: u32) -> Children<'_> {
+        Children {
+            nodes: self.columns(),
+            current: self.columns().firstChildIndex[node_index as usize],
+        }
+    }
+
+    /// Iterates over node_index and all of its descendants in depth first order, parents first
+    pub fn depth_first(&self, node_index: u32) -> DepthFirst<'_> {
+        DepthFirst {
+            nodes: self.columns(),
+            root: node_index,
+            stack: vec![node_index],
+        }
+    }
+
+    /// Iterates over node_index and all of its descendants in breadth first order
+    pub fn breadth_first(&self, node_index: u32) -> BreadthFirst<'_> {
+        BreadthFirst {
+            nodes: self.columns(),
+            queue: VecDeque::from([node_index]),
+        }
+    }
+
+    /// Iterates over the ancestors of node_index, from its parent up to the root of its tree
+    pub fn ancestors(&self, node_index: u32) -> Ancestors<'_> {
+        Ancestors {
+            nodes: self.columns(),
+            current: node_index,
+        }
+    }
+
+    /// Iterates over the descendants of node_index in depth first order, without node_index itself
+    pub fn descendants(&self, node_index: u32) -> impl Iterator<Item = u32> + '_ {
+        self.depth_first(node_index).skip(1)
+    }
+
+    /// Iterates over the nodes that have no parent, which are the roots of all trees
+    pub fn roots(&self) -> impl Iterator<Item = u32> + '_ {
+        self.live_indices()
+            .filter(move |&index| self.columns().parentIndex[index as usize] == INVALID_INDEX)
+    }
+
+    /// Iterates over all nodes belonging to plant, in index order. A plant's nodes may be spread
+    /// over several trees, for example after a branch has been detached
+    pub fn nodes_of_plant(&self, plant: PlantHandle) -> impl Iterator<Item = u32> + '_ {
+        self.live_indices()
+            .filter(move |&index| self.columns().plant_handle(index as usize) == plant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archetype::*;
+    use crate::color::*;
+    use crate::cylinder::*;
+    use crate::fixtures::*;
+
+    /// Builds the tree root -> (a -> c), b and returns the buffer and [root, a, b, c]
+    fn tree(plant: PlantHandle) -> (NodeBuffer, [u32; 4]) {
+        let mut node_buffer = NodeBuffer::new(4);
+        let [root, a, b, c] = [0.0, 0.5, -0.5, 0.0]
+            .map(|angle| organ(&mut node_buffer, plant, STEM_ARCHETYPE_INDEX, angle));
+        node_buffer.add_child(root, a);
+        node_buffer.add_child(root, b);
+        node_buffer.add_child(a, c);
+        (node_buffer, [root, a, b, c])
+    }
+
+    #[test]
+    fn walks_in_each_order() {
+        let (_, plant) = living_plant();
+        let (node_buffer, [root, a, b, c]) = tree(plant);
+        let walk = |iter: &mut dyn Iterator<Item = u32>| iter.collect::<Vec<_>>();
+        assert_eq!(walk(&mut node_buffer.children(root)), [a, b]);
+        assert_eq!(walk(&mut node_buffer.depth_first(root)), [root, a, c, b]);
+        assert_eq!(walk(&mut node_buffer.breadth_first(root)), [root, a, b, c]);
+        assert_eq!(walk(&mut node_buffer.descendants(root)), [a, c, b]);
+        assert_eq!(walk(&mut node_buffer.ancestors(c)), [a, root]);
+        assert_eq!(walk(&mut node_buffer.roots()), [root]);
+        // Siblings of the node a walk starts from are not part of its subtree
+        assert_eq!(walk(&mut node_buffer.depth_first(a)), [a, c]);
+        assert_eq!(walk(&mut node_buffer.breadth_first(a)), [a, c]);
+    }
+
+    #[test]
+    fn finds_the_nodes_of_a_plant_across_trees() {
+        let (_, [first, second]) = living_plants([[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]]);
+        let (mut node_buffer, [root, a, b, c]) = tree(first);
+        let other = organ(&mut node_buffer, second, LEAF_ARCHETYPE_INDEX, 0.0);
+        node_buffer.add_child(b, other);
+        node_buffer.detach(a);
+
+        let mut nodes: Vec<u32> = node_buffer.nodes_of_plant(first).collect();
+        nodes.sort();
+        let mut expected = [root, a, b, c];
+        expected.sort();
+        assert_eq!(nodes, expected);
+        assert_eq!(
+            node_buffer.nodes_of_plant(second).collect::<Vec<_>>(),
+            [other]
+        );
+    }
+
+    #[test]
+    fn deep_plants_do_not_overflow_the_stack() {
+        const DEPTH: u32 = 50_000;
+        let (plant_buffer, plant) = living_plant();
+        let mut node_buffer = NodeBuffer::new(DEPTH);
+        let root = stem(&mut node_buffer, plant, DEPTH);
+        let top = node_buffer.depth_first(root).last().unwrap();
+        assert_eq!(node_buffer.breadth_first(root).count(), DEPTH as usize);
+        assert_eq!(node_buffer.ancestors(top).count(), DEPTH as usize - 1);
+
+        let vertices = node_buffer.gen_vertex(
+            &plant_buffer,
+            &ArchetypeTable::new(),
+            &CylinderStyle::new(),
+            &NodeColors::Archetype(Shading::new()),
+        );
+        assert!(!vertices.is_empty());
+        assert!((node_buffer.frame(top).tip.y - DEPTH as f32).abs() < 1.0);
+    }
+}
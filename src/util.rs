@@ -0,0 +1,63 @@
+#![allow(dead_code)]
+use cgmath::{InnerSpace, Matrix4, One, Quaternion, Vector3, Vector4};
+
+/// Converts a column major array into a matrix
+pub fn tomat(mat: [[f32; 4]; 4]) -> Matrix4<f32> {
+    Matrix4::from_cols(
+        Vector4::from(mat[0]),
+        Vector4::from(mat[1]),
+        Vector4::from(mat[2]),
+        Vector4::from(mat[3]),
+    )
+}
+
+pub fn tov(v3: [f32; 3]) -> Vector3<f32> {
+    Vector3::new(v3[0], v3[1], v3[2])
+}
+
+pub fn to3(v: Vector3<f32>) -> [f32; 3] {
+    [v.x, v.y, v.z]
+}
+
+pub fn scale3(a: [f32; 3], scalar: f32) -> [f32; 3] {
+    [a[0] * scalar, a[1] * scalar, a[2] * scalar]
+}
+
+pub fn add3(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+/// Scales q back to unit length, undoing the drift that builds up as rotations are composed.
+/// Quaternions too short to be scaled become the identity rotation
+pub fn renormalize(q: Quaternion<f32>) -> Quaternion<f32> {
+    let magnitude = q.magnitude();
+    if magnitude > f32::EPSILON && magnitude.is_finite() {
+        q / magnitude
+    } else {
+        Quaternion::one()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renormalize_scales_to_unit_length() {
+        let q = Quaternion::new(2.0, 0.0, 2.0, 0.0);
+        let unit = renormalize(q);
+        assert!((unit.magnitude() - 1.0).abs() < 1.0e-6);
+        assert!((unit - q / q.magnitude()).magnitude() < 1.0e-6);
+    }
+
+    #[test]
+    fn degenerate_quaternions_become_the_identity() {
+        for q in [
+            Quaternion::new(0.0, 0.0, 0.0, 0.0),
+            Quaternion::new(f32::NAN, 0.0, 0.0, 0.0),
+            Quaternion::new(f32::INFINITY, 0.0, 0.0, 0.0),
+        ] {
+            assert_eq!(renormalize(q), Quaternion::one());
+        }
+    }
+}
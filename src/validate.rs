@@ -0,0 +1,323 @@
+#![allow(dead_code)]
+use std::fmt;
+
+use super::node::*;
+use super::plant::*;
+use super::slot::*;
+
+/// A broken invariant found by validate. Indices refer to slots of the buffer being validated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The free stack holds an index outside of the buffer
+    InvalidFreeSlot(u32),
+    /// The slot is on the free stack more than once, meaning it has been freed twice
+    DoubleFree(u32),
+    /// The slot is on the free stack, but holds an entry that is not garbage
+    LiveEntryFreed(u32),
+    /// The slot holds garbage, but is not on the free stack, so it can never be allocated again
+    LeakedSlot(u32),
+    /// The entry has a status that is not one of the STATUS constants
+    InvalidStatus { index: u32, status: u32 },
+    /// The node links to an index outside of the buffer
+    DanglingLink { node: u32, link: u32 },
+    /// The node links to a node that is garbage
+    GarbageLink { node: u32, link: u32 },
+    /// The child is listed more than once among the children of the parent
+    DuplicateChild { parent: u32, child: u32 },
+    /// The parent lists the child as one of its children, but the child has another parent
+    ParentMismatch { parent: u32, child: u32 },
+    /// The node names the parent as its parent, but the parent doesn't list it as a child
+    NotAChild { node: u32, parent: u32 },
+    /// The node has no parent, but links to a sibling
+    OrphanSibling { node: u32, sibling: u32 },
+    /// The node is reached twice while walking down from the roots, so it is part of a cycle or
+    /// has two parents
+    Revisited(u32),
+    /// The node can't be reached from any root, so it is part of a cycle
+    Unreachable(u32),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::InvalidFreeSlot(index) => {
+                write!(f, "Free stack holds invalid index {}", index)
+            }
+            Violation::DoubleFree(index) => write!(f, "Slot {} has been freed twice", index),
+            Violation::LiveEntryFreed(index) => {
+                write!(f, "Slot {} is on the free stack but still in use", index)
+            }
+            Violation::LeakedSlot(index) => {
+                write!(f, "Slot {} is garbage but not on the free stack", index)
+            }
+            Violation::InvalidStatus { index, status } => {
+                write!(f, "Slot {} has invalid status {}", index, status)
+            }
+            Violation::DanglingLink { node, link } => {
+                write!(
+                    f,
+                    "Node {} links to index {} outside of the buffer",
+                    node, link
+                )
+            }
+            Violation::GarbageLink { node, link } => {
+                write!(f, "Node {} links to garbage node {}", node, link)
+            }
+            Violation::DuplicateChild { parent, child } => {
+                write!(f, "Node {} has node {} as a child twice", parent, child)
+            }
+            Violation::ParentMismatch { parent, child } => write!(
+                f,
+                "Node {} has node {} as a child, but it has another parent",
+                parent, child
+            ),
+            Violation::NotAChild { node, parent } => write!(
+                f,
+                "Node {} has node {} as its parent, but is not one of its children",
+                node, parent
+            ),
+            Violation::OrphanSibling { node, sibling } => write!(
+                f,
+                "Node {} has no parent, but has node {} as a sibling",
+                node, sibling
+            ),
+            Violation::Revisited(node) => {
+                write!(f, "Node {} is reached twice from the roots", node)
+            }
+            Violation::Unreachable(node) => {
+                write!(f, "Node {} can't be reached from any root", node)
+            }
+        }
+    }
+}
+
+/// Returns true if status is one of the STATUS constants
+fn valid_status(status: u32) -> bool {
+    matches!(
+        status,
+        STATUS_GARBAGE | STATUS_DEAD | STATUS_ALIVE | STATUS_NEVER_ALIVE | STATUS_DORMANT
+    )
+}
+
+impl<S: Storage> SlotBuffer<S> {
+    /// Checks that every slot is either on the free stack exactly once and garbage, or in use and
+    /// not on the free stack
+    pub fn validate_slots(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let mut free = vec![false; self.size() as usize];
+        for &index in self.free_slots() {
+            if index >= self.size() {
+                violations.push(Violation::InvalidFreeSlot(index));
+            } else if free[index as usize] {
+                violations.push(Violation::DoubleFree(index));
+            } else {
+                free[index as usize] = true;
+                if !self.is_garbage(index) {
+                    violations.push(Violation::LiveEntryFreed(index));
+                }
+            }
+        }
+        for index in 0..self.size() {
+            if self.is_garbage(index) && !free[index as usize] {
+                violations.push(Violation::LeakedSlot(index));
+            }
+        }
+        violations
+    }
+}
+
+impl NodeBuffer {
+    /// Checks the structure of the buffer, returning every broken invariant that was found. Links
+    /// between nodes must agree in both directions, must not point at garbage, and must form
+    /// trees without cycles
+    pub fn validate(&self) -> Vec<Violation> {
+        let mut violations = self.validate_slots();
+        let nodes = self.columns();
+        // Returns true if link can be followed
+        let live = |link: u32| link < self.size() && !self.is_garbage(link);
+
+        // The parent each node is listed under, used to stop on lists of children that loop
+        let mut listed = vec![INVALID_INDEX; self.size() as usize];
+        for ni in self.live_indices() {
+            let i = ni as usize;
+            if !valid_status(nodes.status[i]) {
+                violations.push(Violation::InvalidStatus {
+                    index: ni,
+                    status: nodes.status[i],
+                });
+            }
+            let parent = nodes.parentIndex[i];
+            for link in [parent, nodes.firstChildIndex[i], nodes.nextSiblingIndex[i]] {
+                if link == INVALID_INDEX {
+                    continue;
+                }
+                if link >= self.size() {
+                    violations.push(Violation::DanglingLink { node: ni, link });
+                } else if self.is_garbage(link) {
+                    violations.push(Violation::GarbageLink { node: ni, link });
+                }
+            }
+            if parent == INVALID_INDEX && nodes.nextSiblingIndex[i] != INVALID_INDEX {
+                violations.push(Violation::OrphanSibling {
+                    node: ni,
+                    sibling: nodes.nextSiblingIndex[i],
+                });
+            }
+            let mut child = nodes.firstChildIndex[i];
+            while live(child) {
+                if listed[child as usize] == ni {
+                    violations.push(Violation::DuplicateChild { parent: ni, child });
+                    break;
+                }
+                if listed[child as usize] != INVALID_INDEX {
+                    // Listed by two parents, which the walk from the roots reports
+                    break;
+                }
+                listed[child as usize] = ni;
+                if nodes.parentIndex[child as usize] != ni {
+                    violations.push(Violation::ParentMismatch { parent: ni, child });
+                }
+                child = nodes.nextSiblingIndex[child as usize];
+            }
+        }
+        for ni in self.live_indices() {
+            let parent = nodes.parentIndex[ni as usize];
+            if live(parent) && listed[ni as usize] != parent {
+                violations.push(Violation::NotAChild { node: ni, parent });
+            }
+        }
+
+        // Walk down from the roots, marking every node reached. Every node is reached through
+        // either its parent's first child link or its previous sibling's link, so a node reached
+        // twice has two of them. The traversal iterators can't be used here, since they would
+        // loop forever on a cycle
+        let mut reached = vec![false; self.size() as usize];
+        let mut stack: Vec<u32> = self.roots().collect();
+        while let Some(index) = stack.pop() {
+            if reached[index as usize] {
+                violations.push(Violation::Revisited(index));
+                continue;
+            }
+            reached[index as usize] = true;
+            let child = nodes.firstChildIndex[index as usize];
+            if live(child) {
+                stack.push(child);
+            }
+            // Siblings of roots are reported above, and are not part of any tree
+            let sibling = nodes.nextSiblingIndex[index as usize];
+            if live(sibling) && nodes.parentIndex[index as usize] != INVALID_INDEX {
+                stack.push(sibling);
+            }
+        }
+        for ni in self.live_indices() {
+            if !reached[ni as usize] {
+                violations.push(Violation::Unreachable(ni));
+            }
+        }
+        violations
+    }
+}
+
+impl PlantBuffer {
+    /// Checks the structure of the buffer, returning every broken invariant that was found
+    pub fn validate(&self) -> Vec<Violation> {
+        let mut violations = self.validate_slots();
+        for (index, plant) in self.iter() {
+            if !valid_status(plant.status) {
+                violations.push(Violation::InvalidStatus {
+                    index,
+                    status: plant.status,
+                });
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archetype::*;
+    use crate::fixtures::*;
+
+    /// Returns a plant buffer with two living plants side by side, and a node buffer with a stem
+    /// of three segments on each. Returns the roots of both stems as well
+    fn two_plants() -> (NodeBuffer, PlantBuffer, [u32; 2]) {
+        let (plant_buffer, plants) = living_plants([[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]]);
+        let mut node_buffer = NodeBuffer::new(4);
+        let roots = plants.map(|plant| stem(&mut node_buffer, plant, 3));
+        (node_buffer, plant_buffer, roots)
+    }
+
+    #[test]
+    fn edits_keep_buffers_valid() {
+        let archetype_table = ArchetypeTable::new();
+        let archetype = archetype_table.get(GROWING_BUD_ARCHETYPE_INDEX).unwrap();
+        let (mut node_buffer, plant_buffer, [root, other_root]) = two_plants();
+        assert_eq!(node_buffer.validate(), []);
+        assert_eq!(plant_buffer.validate(), []);
+        let top = node_buffer.depth_first(root).last().unwrap();
+
+        let (_, upper) = node_buffer
+            .divide(0.5, root, SplitChildren::LateralsLower)
+            .unwrap();
+        assert_eq!(node_buffer.validate(), []);
+        node_buffer
+            .insert_lateral(upper.index, 0.3, archetype, 1.0)
+            .unwrap();
+        assert_eq!(node_buffer.validate(), []);
+        let copy = node_buffer.clone_subtree(root, None).unwrap();
+        assert_eq!(node_buffer.validate(), []);
+        node_buffer.free_subtree(copy).unwrap();
+        assert_eq!(node_buffer.validate(), []);
+
+        // The second plant's stem is moved onto the tip of the first one's
+        node_buffer.graft(other_root, top, &plant_buffer).unwrap();
+        assert_eq!(node_buffer.validate(), []);
+        assert_eq!(node_buffer.roots().count(), 1);
+
+        node_buffer.compact();
+        assert_eq!(node_buffer.validate(), []);
+        assert_eq!(plant_buffer.validate(), []);
+    }
+
+    #[test]
+    fn broken_links_are_reported() {
+        let (mut node_buffer, _, roots) = two_plants();
+        let children = roots.map(|root| node_buffer.columns().firstChildIndex[root as usize]);
+
+        // A child claiming another parent
+        node_buffer.columns_mut().parentIndex[children[0] as usize] = roots[1];
+        let violations = node_buffer.validate();
+        assert!(violations.contains(&Violation::ParentMismatch {
+            parent: roots[0],
+            child: children[0]
+        }));
+        assert!(violations.contains(&Violation::NotAChild {
+            node: children[0],
+            parent: roots[1]
+        }));
+        node_buffer.columns_mut().parentIndex[children[0] as usize] = roots[0];
+        assert_eq!(node_buffer.validate(), []);
+
+        // A root made the child of its own child
+        node_buffer.columns_mut().parentIndex[roots[1] as usize] = children[1];
+        node_buffer.columns_mut().firstChildIndex[children[1] as usize] = roots[1];
+        let violations = node_buffer.validate();
+        assert!(violations.contains(&Violation::Unreachable(roots[1])));
+        assert!(violations.contains(&Violation::Unreachable(children[1])));
+    }
+
+    #[test]
+    fn slots_marked_garbage_without_freeing_are_reported() {
+        let (mut node_buffer, _, [root, _]) = two_plants();
+        let child = node_buffer.columns().firstChildIndex[root as usize];
+        node_buffer.columns_mut().status[child as usize] = STATUS_GARBAGE;
+        let violations = node_buffer.validate();
+        assert!(violations.contains(&Violation::LeakedSlot(child)));
+        assert!(violations.contains(&Violation::GarbageLink {
+            node: root,
+            link: child
+        }));
+    }
+}